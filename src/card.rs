@@ -0,0 +1,173 @@
+use crate::{
+    cardholder::Cardholder,
+    error::WorkerError,
+    gpg::{
+        card_status, factory_reset_key, gen_key_on_card, key_to_card, pin_reader, set_card_pins,
+        set_cardholder, set_touch_policies, test_key, KeyProfile, SubkeyFingerprints, SubkeyUsage,
+        TouchPolicies,
+    },
+    gpg_output::CardStatus,
+    pin::CardPins,
+    ykman::{device_info, list_serials, YubiKeyDevice},
+};
+
+/// Operations on OpenPGP application of connected keys. Operations done
+/// through gpg use the session in `gpg_home`, bound to the key by `attach`.
+pub trait CardBackend: Send + Sync {
+    /// Serial numbers of connected keys.
+    fn list_serials(&self) -> Result<Vec<String>, WorkerError>;
+    fn device_info(&self, serial: &str) -> Result<YubiKeyDevice, WorkerError>;
+    /// Makes gpg session use key with given serial.
+    fn attach(&self, gpg_home: &str, serial: &str) -> Result<(), WorkerError>;
+    fn status(&self, gpg_home: &str, serial: &str) -> Result<CardStatus, WorkerError>;
+    /// Factory reset of OpenPGP application, PINs and touch policies included.
+    fn reset(&self, serial: &str) -> Result<(), WorkerError>;
+    /// Moves subkeys generated in gpg home to card slots.
+    fn load_keys(
+        &self,
+        gpg_home: &str,
+        serial: &str,
+        email: &str,
+        subkeys: &SubkeyFingerprints,
+    ) -> Result<(), WorkerError>;
+    /// Generates keys on the card, returns primary key fingerprint.
+    fn generate_keys(
+        &self,
+        gpg_home: &str,
+        serial: &str,
+        full_name: &str,
+        email: &str,
+        profile: &KeyProfile,
+    ) -> Result<String, WorkerError>;
+    fn set_cardholder(
+        &self,
+        gpg_home: &str,
+        serial: &str,
+        cardholder: &Cardholder,
+    ) -> Result<(), WorkerError>;
+    fn set_pins(&self, serial: &str, current: &CardPins, new: &CardPins)
+        -> Result<(), WorkerError>;
+    fn set_touch(
+        &self,
+        serial: &str,
+        policies: &TouchPolicies,
+        admin_pin: &str,
+    ) -> Result<(), WorkerError>;
+    /// Runs test operation with key in slot of given usage.
+    fn test_key(
+        &self,
+        gpg_home: &str,
+        serial: &str,
+        usage: SubkeyUsage,
+        fingerprint: &str,
+        user_pin: &str,
+    ) -> Result<bool, WorkerError>;
+}
+
+/// YubiKeys managed with ykman and gpg.
+pub struct YkmanBackend {
+    gpg_command: String,
+    gpg_debug_level: String,
+}
+
+impl YkmanBackend {
+    pub fn new(gpg_command: &str, gpg_debug_level: &str) -> Self {
+        Self {
+            gpg_command: gpg_command.into(),
+            gpg_debug_level: gpg_debug_level.into(),
+        }
+    }
+}
+
+impl CardBackend for YkmanBackend {
+    fn list_serials(&self) -> Result<Vec<String>, WorkerError> {
+        list_serials()
+    }
+
+    fn device_info(&self, serial: &str) -> Result<YubiKeyDevice, WorkerError> {
+        device_info(serial)
+    }
+
+    fn attach(&self, gpg_home: &str, serial: &str) -> Result<(), WorkerError> {
+        pin_reader(gpg_home, serial)
+    }
+
+    fn status(&self, gpg_home: &str, _serial: &str) -> Result<CardStatus, WorkerError> {
+        card_status(&self.gpg_command, gpg_home)
+    }
+
+    fn reset(&self, serial: &str) -> Result<(), WorkerError> {
+        factory_reset_key(serial)
+    }
+
+    fn load_keys(
+        &self,
+        gpg_home: &str,
+        _serial: &str,
+        email: &str,
+        _subkeys: &SubkeyFingerprints,
+    ) -> Result<(), WorkerError> {
+        key_to_card(&self.gpg_command, &self.gpg_debug_level, gpg_home, email)
+    }
+
+    fn generate_keys(
+        &self,
+        gpg_home: &str,
+        _serial: &str,
+        full_name: &str,
+        email: &str,
+        profile: &KeyProfile,
+    ) -> Result<String, WorkerError> {
+        gen_key_on_card(
+            &self.gpg_command,
+            &self.gpg_debug_level,
+            gpg_home,
+            full_name,
+            email,
+            profile,
+        )
+    }
+
+    fn set_cardholder(
+        &self,
+        gpg_home: &str,
+        _serial: &str,
+        cardholder: &Cardholder,
+    ) -> Result<(), WorkerError> {
+        set_cardholder(
+            &self.gpg_command,
+            &self.gpg_debug_level,
+            gpg_home,
+            cardholder,
+        )
+    }
+
+    fn set_pins(
+        &self,
+        serial: &str,
+        current: &CardPins,
+        new: &CardPins,
+    ) -> Result<(), WorkerError> {
+        set_card_pins(serial, current, new)
+    }
+
+    fn set_touch(
+        &self,
+        serial: &str,
+        policies: &TouchPolicies,
+        admin_pin: &str,
+    ) -> Result<(), WorkerError> {
+        set_touch_policies(serial, policies, admin_pin)
+    }
+
+    fn test_key(
+        &self,
+        gpg_home: &str,
+        _serial: &str,
+        usage: SubkeyUsage,
+        fingerprint: &str,
+        user_pin: &str,
+    ) -> Result<bool, WorkerError> {
+        test_key(&self.gpg_command, gpg_home, usage, fingerprint, user_pin)
+    }
+}
@@ -0,0 +1,159 @@
+use log::warn;
+
+use crate::config::Config;
+use crate::proto;
+
+/// Cardholder data objects stored on the card.
+#[derive(Debug)]
+pub struct Cardholder {
+    pub surname: String,
+    pub given_name: String,
+    pub login: String,
+    pub language: String,
+    pub url: Option<String>,
+}
+
+impl Cardholder {
+    pub fn new(config: &Config, job: &proto::GetJobResponse, fingerprint: &str) -> Self {
+        let url = config.public_key_url.as_ref().map(|template| {
+            template
+                .replace("{email}", &job.email)
+                .replace("{fingerprint}", fingerprint)
+        });
+        let (surname, given_name) = card_name(&job.last_name, &job.first_name);
+        if surname.is_empty() {
+            warn!(
+                "Name of {} has no characters allowed on card, it's left empty",
+                job.email
+            );
+        }
+        Self {
+            surname,
+            given_name,
+            login: job.email.clone(),
+            language: config.card_language.clone(),
+            url,
+        }
+    }
+}
+
+// Card name may contain only plain ASCII and '<' is used as separator ("Last<<First"),
+// diacritics are stripped and remaining characters replaced with space.
+fn transliterate(name: &str) -> String {
+    let mut result = String::new();
+    for c in name.chars() {
+        let replacement = match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+            'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+            'æ' => "ae",
+            'Æ' => "AE",
+            'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+            'Ç' | 'Ć' | 'Ĉ' | 'Ċ' | 'Č' => "C",
+            'ď' | 'đ' | 'ð' => "d",
+            'Ď' | 'Đ' | 'Ð' => "D",
+            'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+            'È' | 'É' | 'Ê' | 'Ë' | 'Ē' | 'Ĕ' | 'Ė' | 'Ę' | 'Ě' => "E",
+            'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+            'Ĝ' | 'Ğ' | 'Ġ' | 'Ģ' => "G",
+            'ĥ' | 'ħ' => "h",
+            'Ĥ' | 'Ħ' => "H",
+            'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+            'Ì' | 'Í' | 'Î' | 'Ï' | 'Ĩ' | 'Ī' | 'Ĭ' | 'Į' | 'İ' => "I",
+            'ĵ' => "j",
+            'Ĵ' => "J",
+            'ķ' => "k",
+            'Ķ' => "K",
+            'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+            'Ĺ' | 'Ļ' | 'Ľ' | 'Ŀ' | 'Ł' => "L",
+            'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+            'Ñ' | 'Ń' | 'Ņ' | 'Ň' => "N",
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+            'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' | 'Ō' | 'Ŏ' | 'Ő' => "O",
+            'œ' => "oe",
+            'Œ' => "OE",
+            'ŕ' | 'ŗ' | 'ř' => "r",
+            'Ŕ' | 'Ŗ' | 'Ř' => "R",
+            'ś' | 'ŝ' | 'ş' | 'š' | 'ș' => "s",
+            'Ś' | 'Ŝ' | 'Ş' | 'Š' | 'Ș' => "S",
+            'ß' => "ss",
+            'ţ' | 'ť' | 'ŧ' | 'ț' => "t",
+            'Ţ' | 'Ť' | 'Ŧ' | 'Ț' => "T",
+            'þ' => "th",
+            'Þ' => "TH",
+            'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+            'Ù' | 'Ú' | 'Û' | 'Ü' | 'Ũ' | 'Ū' | 'Ŭ' | 'Ů' | 'Ű' | 'Ų' => "U",
+            'ŵ' => "w",
+            'Ŵ' => "W",
+            'ý' | 'ÿ' | 'ŷ' => "y",
+            'Ý' | 'Ÿ' | 'Ŷ' => "Y",
+            'ź' | 'ż' | 'ž' => "z",
+            'Ź' | 'Ż' | 'Ž' => "Z",
+            c if c.is_ascii_graphic() && c != '<' => {
+                result.push(c);
+                continue;
+            }
+            _ => " ",
+        };
+        result.push_str(replacement);
+    }
+    result.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// combined "Last<<First" name stored on the card is limited by gpg
+const CARD_NAME_LENGTH: usize = 39;
+
+// transliterated surname and given name fitting on the card, given name is shortened first
+// and takes place of empty surname
+fn card_name(last_name: &str, first_name: &str) -> (String, String) {
+    let mut surname = transliterate(last_name);
+    let mut given_name = transliterate(first_name);
+    if surname.is_empty() {
+        surname = std::mem::take(&mut given_name);
+    }
+    let available = CARD_NAME_LENGTH - "<<".len();
+    surname.truncate(available);
+    given_name.truncate(available - surname.len());
+    (
+        surname.trim_end().to_string(),
+        given_name.trim_end().to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transliterate() {
+        assert_eq!(transliterate("Łukasz Żółć"), "Lukasz Zolc");
+        assert_eq!(transliterate("Müller\tSchmidt"), "Muller Schmidt");
+        assert_eq!(transliterate("Jean-Luc  O'Neill"), "Jean-Luc O'Neill");
+        assert_eq!(transliterate("Smith<<John"), "Smith John");
+        assert_eq!(transliterate("Дмитрий"), "");
+        assert_eq!(transliterate("李 Chen"), "Chen");
+    }
+
+    #[test]
+    fn test_card_name() {
+        assert_eq!(
+            card_name("Kowalski", "Jan"),
+            ("Kowalski".into(), "Jan".into())
+        );
+        // 35 + 2 + 2 characters
+        assert_eq!(
+            card_name("Wolfeschlegelsteinhausenbergerdorff", "Hubert Blaine"),
+            ("Wolfeschlegelsteinhausenbergerdorff".into(), "Hu".into())
+        );
+        assert_eq!(
+            card_name(&"A".repeat(40), "Jan"),
+            ("A".repeat(37), String::new())
+        );
+        // trailing space of shortened name is dropped
+        assert_eq!(
+            card_name(&"B".repeat(30), "Janusz Maria"),
+            ("B".repeat(30), "Janusz".into())
+        );
+        assert_eq!(card_name("Иванов", "Ivan"), ("Ivan".into(), String::new()));
+        assert_eq!(card_name("Иванов", "Иван"), (String::new(), String::new()));
+    }
+}
@@ -1,9 +1,94 @@
 use std::{fs, path::PathBuf};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use serde::Deserialize;
 
-use crate::error::WorkerError;
+use crate::{
+    error::WorkerError,
+    pin::{PinCharset, MIN_ADMIN_PIN_LENGTH, MIN_RESET_CODE_LENGTH, MIN_USER_PIN_LENGTH},
+    ykman::FirmwareVersion,
+};
+
+/// Key algorithm used for generated primary key and subkeys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyAlgorithm {
+    /// Ed25519 for signing and authentication, Cv25519 for encryption
+    Ed25519,
+    Rsa2048,
+    Rsa3072,
+    #[default]
+    Rsa4096,
+}
+
+impl KeyAlgorithm {
+    pub fn rsa_length(self) -> Option<u16> {
+        match self {
+            Self::Ed25519 => None,
+            Self::Rsa2048 => Some(2048),
+            Self::Rsa3072 => Some(3072),
+            Self::Rsa4096 => Some(4096),
+        }
+    }
+
+    /// Oldest firmware supporting the algorithm, RSA 3072 and 4096 came with YubiKey 4.
+    pub fn min_firmware(self) -> FirmwareVersion {
+        match self {
+            Self::Ed25519 => FirmwareVersion::new(5, 2, 3),
+            Self::Rsa2048 => FirmwareVersion::new(0, 0, 0),
+            Self::Rsa3072 | Self::Rsa4096 => FirmwareVersion::new(4, 0, 0),
+        }
+    }
+}
+
+/// What to do with keys that already hold OpenPGP keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResetPolicy {
+    /// Never wipe keys holding OpenPGP keys
+    #[default]
+    Refuse,
+    /// Wipe only keys provisioned before for the same user (card login matches job email).
+    /// Weaker stand-in for an explicit job flag, which the worker proto doesn't carry:
+    /// anyone knowing the admin PIN of a card can set its login.
+    SameUser,
+    /// Always wipe
+    Allow,
+}
+
+/// Where keys are generated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyGenerationMode {
+    /// Generate keys in temporary gpg home and move them to the card
+    #[default]
+    Host,
+    /// Generate keys on the card, private keys never leave the YubiKey
+    Card,
+}
+
+/// Touch policy of OpenPGP card slot, `fixed` variants can be changed only by reset.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TouchPolicy {
+    #[default]
+    Off,
+    On,
+    Fixed,
+    Cached,
+    CachedFixed,
+}
+
+impl TouchPolicy {
+    /// Oldest firmware supporting the policy, None when no support is needed.
+    pub fn min_firmware(self) -> Option<FirmwareVersion> {
+        match self {
+            Self::Off => None,
+            Self::On | Self::Fixed => Some(FirmwareVersion::new(4, 2, 0)),
+            Self::Cached | Self::CachedFixed => Some(FirmwareVersion::new(5, 2, 1)),
+        }
+    }
+}
 
 #[derive(Debug, Parser, Clone, Deserialize)]
 #[clap(about = "Defguard YubiKey Provisioning service")]
@@ -35,6 +120,16 @@ pub struct Config {
     #[arg(long, env = "YUBIKEY_RETRY_INTERVAL", default_value = "15")]
     pub smartcard_retry_interval: u64,
 
+    /// Serial of key to provision, when not set the first connected key not provisioned
+    /// by this worker is used, skipping keys that hold keys protected by reset policy
+    #[arg(long, env = "YUBIKEY_SERIAL")]
+    pub yubikey_serial: Option<String>,
+
+    /// Maximal number of jobs provisioned at once, each one on a different key
+    #[arg(long, env = "MAX_PARALLEL_JOBS", default_value = "1")]
+    #[serde(default = "default_max_parallel_jobs")]
+    pub max_parallel_jobs: usize,
+
     /// gpg debug level, this is set to advanced when log_level is set to debug
     #[arg(long, env = "GPG_DEBUG_LEVEL", default_value = "none")]
     pub gpg_debug_level: String,
@@ -49,6 +144,129 @@ pub struct Config {
     )]
     pub token: String,
 
+    /// Algorithm of generated keys
+    #[arg(long, env = "KEY_ALGORITHM", value_enum, default_value = "rsa4096")]
+    #[serde(default)]
+    pub key_algorithm: KeyAlgorithm,
+
+    /// Expiration of generated keys in gpg format, e.g. "0" (never), "2y", "365d"
+    #[arg(long, env = "KEY_EXPIRY", default_value = "0")]
+    #[serde(default = "default_key_expiry")]
+    pub key_expiry: String,
+
+    /// Generate keys on host and move them to the card, or directly on the card
+    #[arg(long, env = "KEY_GENERATION", value_enum, default_value = "host")]
+    #[serde(default)]
+    pub key_generation: KeyGenerationMode,
+
+    /// Policy for keys that already hold OpenPGP keys: refuse, same-user or allow
+    #[arg(long, env = "RESET_POLICY", value_enum, default_value = "refuse")]
+    #[serde(default)]
+    pub reset_policy: ResetPolicy,
+
+    /// Touch policy of signature slot
+    #[arg(long, env = "TOUCH_SIGN", value_enum, default_value = "off")]
+    #[serde(default)]
+    pub touch_sign: TouchPolicy,
+
+    /// Touch policy of encryption slot
+    #[arg(long, env = "TOUCH_ENCRYPT", value_enum, default_value = "off")]
+    #[serde(default)]
+    pub touch_encrypt: TouchPolicy,
+
+    /// Touch policy of authentication slot
+    #[arg(long, env = "TOUCH_AUTH", value_enum, default_value = "off")]
+    #[serde(default)]
+    pub touch_auth: TouchPolicy,
+
+    /// Preferred languages stored on the card, up to four ISO 639-1 codes, e.g. "en" or "plen"
+    #[arg(long, env = "CARD_LANGUAGE", default_value = "en")]
+    #[serde(default = "default_card_language")]
+    pub card_language: String,
+
+    /// Public key URL stored on the card, {email} and {fingerprint} are replaced with key data
+    #[arg(long, env = "PUBLIC_KEY_URL")]
+    pub public_key_url: Option<String>,
+
+    /// Write the same subkeys to a second, backup key in each job
+    #[arg(long, env = "BACKUP_CARD", default_value_t = false)]
+    #[serde(default)]
+    pub backup_card: bool,
+
+    /// Number of retries while waiting for the backup key, it is usually plugged in
+    /// only after the primary key is done
+    #[arg(long, env = "BACKUP_CARD_RETRIES", default_value = "40")]
+    #[serde(default = "default_backup_card_retries")]
+    pub backup_card_retries: u64,
+
+    /// Replace factory PINs with random user PIN, admin PIN and reset code
+    #[arg(long, env = "RANDOMIZE_PINS", default_value_t = false)]
+    #[serde(default)]
+    pub randomize_pins: bool,
+
+    /// Length of generated user PIN
+    #[arg(long, env = "USER_PIN_LENGTH", default_value = "6")]
+    #[serde(default = "default_user_pin_length")]
+    pub user_pin_length: usize,
+
+    /// Length of generated admin PIN
+    #[arg(long, env = "ADMIN_PIN_LENGTH", default_value = "8")]
+    #[serde(default = "default_admin_pin_length")]
+    pub admin_pin_length: usize,
+
+    /// Length of generated reset code
+    #[arg(long, env = "RESET_CODE_LENGTH", default_value = "8")]
+    #[serde(default = "default_reset_code_length")]
+    pub reset_code_length: usize,
+
+    /// Characters used in generated PINs
+    #[arg(long, env = "PIN_CHARSET", value_enum, default_value = "numeric")]
+    #[serde(default)]
+    pub pin_charset: PinCharset,
+
+    /// Public key file, generated PINs are encrypted to it
+    #[arg(long, env = "PIN_RECIPIENT_KEY")]
+    pub pin_recipient_key: Option<PathBuf>,
+
+    /// Directory where generated PINs are saved
+    #[arg(long, env = "PIN_OUTPUT_DIR")]
+    pub pin_output_dir: Option<PathBuf>,
+
+    /// Public key files, revocation certificate and generated secret subkeys are encrypted
+    /// to them and kept as backup. Secret subkeys are kept only when keys are generated on host.
+    #[arg(long, env = "ESCROW_RECIPIENT_KEYS", value_delimiter = ',')]
+    #[serde(default)]
+    pub escrow_recipient_keys: Vec<PathBuf>,
+
+    /// Directory where encrypted escrow backups are saved
+    #[arg(long, env = "ESCROW_DIR")]
+    pub escrow_dir: Option<PathBuf>,
+
+    /// Directory where revocation certificates are saved when escrow is not configured,
+    /// required then as revocation certificates are not sent to the server
+    #[arg(long, env = "REVOCATION_DIR")]
+    pub revocation_dir: Option<PathBuf>,
+
+    /// Number of seconds after which gpg and ykman commands are killed
+    #[arg(long, env = "COMMAND_TIMEOUT", default_value = "60")]
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout: u64,
+
+    /// Number of seconds after which key generation is killed, RSA keys generated on card take minutes
+    #[arg(long, env = "KEY_GENERATION_TIMEOUT", default_value = "900")]
+    #[serde(default = "default_key_generation_timeout")]
+    pub key_generation_timeout: u64,
+
+    /// Number of seconds after which moving keys to the card is killed
+    #[arg(long, env = "KEY_TO_CARD_TIMEOUT", default_value = "300")]
+    #[serde(default = "default_key_to_card_timeout")]
+    pub key_to_card_timeout: u64,
+
+    /// Number of seconds after which factory reset of the key is killed
+    #[arg(long, env = "RESET_TIMEOUT", default_value = "120")]
+    #[serde(default = "default_reset_timeout")]
+    pub reset_timeout: u64,
+
     #[arg(
         long = "skip-permissions",
         env = "SKIP_GPG_PERMISSIONS",
@@ -69,15 +287,86 @@ impl Default for Config {
             url: "http://127.0.0.1:50055".into(),
             smartcard_retries: 1,
             smartcard_retry_interval: 15,
+            yubikey_serial: None,
+            max_parallel_jobs: default_max_parallel_jobs(),
             token: "TOKEN".into(),
             config_path: None,
             grpc_ca: None,
             skip_gpg_permissions: false,
             gpg_debug_level: "none".into(),
+            key_algorithm: KeyAlgorithm::Rsa4096,
+            key_expiry: default_key_expiry(),
+            key_generation: KeyGenerationMode::Host,
+            reset_policy: ResetPolicy::Refuse,
+            touch_sign: TouchPolicy::Off,
+            touch_encrypt: TouchPolicy::Off,
+            touch_auth: TouchPolicy::Off,
+            card_language: default_card_language(),
+            public_key_url: None,
+            backup_card: false,
+            backup_card_retries: default_backup_card_retries(),
+            randomize_pins: false,
+            user_pin_length: default_user_pin_length(),
+            admin_pin_length: default_admin_pin_length(),
+            reset_code_length: default_reset_code_length(),
+            pin_charset: PinCharset::Numeric,
+            pin_recipient_key: None,
+            pin_output_dir: None,
+            escrow_recipient_keys: Vec::new(),
+            escrow_dir: None,
+            revocation_dir: None,
+            command_timeout: default_command_timeout(),
+            key_generation_timeout: default_key_generation_timeout(),
+            key_to_card_timeout: default_key_to_card_timeout(),
+            reset_timeout: default_reset_timeout(),
         }
     }
 }
 
+fn default_key_expiry() -> String {
+    "0".into()
+}
+
+fn default_user_pin_length() -> usize {
+    MIN_USER_PIN_LENGTH
+}
+
+fn default_admin_pin_length() -> usize {
+    MIN_ADMIN_PIN_LENGTH
+}
+
+fn default_reset_code_length() -> usize {
+    MIN_RESET_CODE_LENGTH
+}
+
+fn default_card_language() -> String {
+    "en".into()
+}
+
+fn default_backup_card_retries() -> u64 {
+    40
+}
+
+fn default_max_parallel_jobs() -> usize {
+    1
+}
+
+fn default_command_timeout() -> u64 {
+    60
+}
+
+fn default_key_generation_timeout() -> u64 {
+    900
+}
+
+fn default_key_to_card_timeout() -> u64 {
+    300
+}
+
+fn default_reset_timeout() -> u64 {
+    120
+}
+
 pub fn get_config() -> Result<Config, WorkerError> {
     // parse CLI arguments to get config file path
     let mut cli_config = Config::parse();
@@ -95,5 +384,79 @@ pub fn get_config() -> Result<Config, WorkerError> {
         cli_config.gpg_debug_level = "advanced".into();
     }
 
+    if cli_config.randomize_pins {
+        if cli_config.user_pin_length < MIN_USER_PIN_LENGTH
+            || cli_config.admin_pin_length < MIN_ADMIN_PIN_LENGTH
+            || cli_config.reset_code_length < MIN_RESET_CODE_LENGTH
+        {
+            return Err(WorkerError::InvalidConfigFile(format!(
+                "PIN lengths below card minimum (user {MIN_USER_PIN_LENGTH}, admin \
+                {MIN_ADMIN_PIN_LENGTH}, reset code {MIN_RESET_CODE_LENGTH})"
+            )));
+        }
+        // generated PINs would be lost otherwise
+        if cli_config.pin_output_dir.is_none() {
+            return Err(WorkerError::InvalidConfigFile(
+                "Randomized PINs require PIN output directory".into(),
+            ));
+        }
+    }
+
+    // gpg accepts up to four two-letter codes
+    let language = &cli_config.card_language;
+    if !(2..=8).contains(&language.len())
+        || language.len() % 2 != 0
+        || !language.bytes().all(|c| c.is_ascii_lowercase())
+    {
+        return Err(WorkerError::InvalidConfigFile(format!(
+            "Card language must be 2 to 8 lowercase letters (ISO 639-1 codes), got {language:?}"
+        )));
+    }
+
+    if cli_config.max_parallel_jobs == 0 {
+        return Err(WorkerError::InvalidConfigFile(
+            "At least one parallel job is required".into(),
+        ));
+    }
+
+    // parallel jobs would compete for the same key
+    if cli_config.yubikey_serial.is_some() && cli_config.max_parallel_jobs > 1 {
+        return Err(WorkerError::InvalidConfigFile(
+            "Requested key serial allows only one parallel job".into(),
+        ));
+    }
+
+    if cli_config.backup_card && cli_config.key_generation == KeyGenerationMode::Card {
+        return Err(WorkerError::InvalidConfigFile(
+            "Backup key requires keys generated on host".into(),
+        ));
+    }
+
+    if !cli_config.escrow_recipient_keys.is_empty() && cli_config.escrow_dir.is_none() {
+        return Err(WorkerError::InvalidConfigFile(
+            "Escrow requires escrow directory".into(),
+        ));
+    }
+
+    // revocation certificates would be lost otherwise
+    if cli_config.escrow_recipient_keys.is_empty() && cli_config.revocation_dir.is_none() {
+        return Err(WorkerError::InvalidConfigFile(
+            "Revocation certificate directory is required when escrow is not configured".into(),
+        ));
+    }
+
+    if [
+        cli_config.command_timeout,
+        cli_config.key_generation_timeout,
+        cli_config.key_to_card_timeout,
+        cli_config.reset_timeout,
+    ]
+    .contains(&0)
+    {
+        return Err(WorkerError::InvalidConfigFile(
+            "Command timeouts must be greater than zero".into(),
+        ));
+    }
+
     Ok(cli_config)
 }
@@ -3,6 +3,8 @@ use std::{str::Utf8Error, string::FromUtf8Error};
 use thiserror::Error;
 use tonic::Status;
 
+use crate::stage::Stage;
+
 #[derive(Debug, Error)]
 pub enum WorkerError {
     #[error("Invalid config file. Error: {0}")]
@@ -11,22 +13,54 @@ pub enum WorkerError {
     TonicError(String),
     #[error("Tonic request failed with status code of {0}")]
     TonicStatusError(String),
-    #[error("GPG command failed")]
-    Gpg,
+    #[error("GPG command failed: {0}")]
+    Gpg(String),
     #[error("Failed to clean up gpg session")]
     GPGSessionEnd,
-    #[error("ykman command failed")]
-    YubikeyManager,
+    #[error("ykman command failed: {0}")]
+    YubikeyManager(String),
     #[error("No YubiKeys found")]
     NoKeysFound,
-    #[error("Multiple yubikeys found")]
-    MultipleKeysPresent,
     #[error("IO error occurred: {0}")]
     IO(String),
     #[error("UTF8 conversion failed")]
     UTF8Conversion,
     #[error("Cannot find key serial number")]
     SerialNotFound,
+    #[error("Key registry is unavailable")]
+    Registry,
+    #[error("OpenPGP application is disabled on key {0}")]
+    OpenPgpDisabled(String),
+    #[error("Key {serial} with firmware {firmware} cannot hold {requirement}")]
+    UnsupportedFirmware {
+        serial: String,
+        firmware: String,
+        requirement: String,
+    },
+    #[error(
+        "Key {serial} already holds keys of '{cardholder}' (signature counter: {signature_counter}), refusing to reset it"
+    )]
+    KeyInUse {
+        serial: String,
+        cardholder: String,
+        signature_counter: u64,
+    },
+    #[error("Verification of key {serial} failed: {reason}")]
+    VerificationFailed { serial: String, reason: String },
+    #[error("{step} timed out")]
+    Timeout { step: String },
+    #[error("{stage} stage failed: {error}")]
+    StageFailed {
+        stage: Stage,
+        error: Box<WorkerError>,
+    },
+    #[error("Provisioning job aborted: {0}")]
+    JobAborted(String),
+    #[error("{error} ({rollback})")]
+    ProvisioningFailed {
+        error: Box<WorkerError>,
+        rollback: String,
+    },
 }
 
 impl From<tonic::transport::Error> for WorkerError {
@@ -1,25 +1,28 @@
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
 use std::{
     env, fs,
-    io::Write,
-    path::Path,
-    process::{Child, Command, Stdio},
-    time::Duration,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
 };
-#[cfg(target_family = "unix")]
-use std::{os::unix::fs::PermissionsExt, path::PathBuf};
 
-#[cfg(target_family = "unix")]
-use log::error;
-use log::{debug, info};
-use serde::Serialize;
-use tokio::time::interval;
+use log::{debug, error, warn};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
 use which::which;
 
-use crate::config::Config;
+use crate::cardholder::Cardholder;
+use crate::config::{Config, KeyAlgorithm, TouchPolicy};
 use crate::error::WorkerError;
-use crate::proto;
+use crate::gpg_output::{
+    created_primary, parse_card_status, parse_key_listing, parse_status, CardStatus, PublicKey,
+    StatusEvent,
+};
+use crate::pin::CardPins;
+use crate::process::{failure_details, run, Step};
+use crate::ykman::reader_name;
 
 pub const ADMIN_PIN: &str = "12345678";
+pub const USER_PIN: &str = "123456";
 
 pub fn get_gpg_command() -> &'static str {
     if which("gpg").is_err() {
@@ -32,102 +35,378 @@ pub fn get_gpg_command() -> &'static str {
     "gpg"
 }
 
-pub fn card_info_args(name: &str, email: &str) -> String {
+impl KeyAlgorithm {
+    // key type lines for gpg batch parameters, prefix is either "Key" or "Subkey"
+    fn batch_params(self, prefix: &str) -> String {
+        match self.rsa_length() {
+            Some(length) => format!("{prefix}-Type: RSA\n    {prefix}-Length: {length}"),
+            None => format!("{prefix}-Type: EDDSA\n    {prefix}-Curve: ed25519"),
+        }
+    }
+
+    // algorithm name accepted by gpg --quick-add-key
+    fn subkey_algorithm(self, usage: SubkeyUsage) -> String {
+        match (self.rsa_length(), usage) {
+            (Some(length), _) => format!("rsa{length}"),
+            (None, SubkeyUsage::Encrypt) => "cv25519".into(),
+            (None, _) => "ed25519".into(),
+        }
+    }
+
+    // answers for single slot in gpg --card-edit key-attr flow
+    fn card_key_attr(self) -> String {
+        match self.rsa_length() {
+            // (1) RSA, then key size
+            Some(length) => format!("1\n{length}"),
+            // (2) ECC, then (1) Curve 25519
+            None => "2\n1".into(),
+        }
+    }
+}
+
+impl TouchPolicy {
+    fn as_ykman_policy(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::On => "on",
+            Self::Fixed => "fixed",
+            Self::Cached => "cached",
+            Self::CachedFixed => "cached-fixed",
+        }
+    }
+}
+
+/// Parameters of keys generated for a job.
+#[derive(Debug, Clone)]
+pub struct KeyProfile {
+    pub algorithm: KeyAlgorithm,
+    /// Expiration in gpg format, e.g. "0" (never), "2y", "365d" or ISO date
+    pub expiry: String,
+}
+
+impl From<&Config> for KeyProfile {
+    fn from(config: &Config) -> Self {
+        Self {
+            algorithm: config.key_algorithm,
+            expiry: config.key_expiry.clone(),
+        }
+    }
+}
+
+/// Touch policies applied to each card slot.
+#[derive(Debug, Clone, Copy)]
+pub struct TouchPolicies {
+    pub sign: TouchPolicy,
+    pub encrypt: TouchPolicy,
+    pub auth: TouchPolicy,
+}
+
+impl From<&Config> for TouchPolicies {
+    fn from(config: &Config) -> Self {
+        Self {
+            sign: config.touch_sign,
+            encrypt: config.touch_encrypt,
+            auth: config.touch_auth,
+        }
+    }
+}
+
+/// Capability of a subkey, each one is stored in separate OpenPGP card slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubkeyUsage {
+    Sign,
+    Encrypt,
+    Auth,
+}
+
+impl SubkeyUsage {
+    fn as_gpg_usage(self) -> &'static str {
+        match self {
+            Self::Sign => "sign",
+            Self::Encrypt => "encr",
+            Self::Auth => "auth",
+        }
+    }
+
+    // key slot name used by ykman
+    fn as_ykman_slot(self) -> &'static str {
+        match self {
+            Self::Sign => "sig",
+            Self::Encrypt => "enc",
+            Self::Auth => "aut",
+        }
+    }
+
+    // parse capabilities field of gpg --with-colons pub/sub record,
+    // uppercase letters describe the whole key so only lowercase ones are checked
+    fn from_capabilities(capabilities: &str) -> Option<Self> {
+        if capabilities.contains('s') {
+            Some(Self::Sign)
+        } else if capabilities.contains('e') {
+            Some(Self::Encrypt)
+        } else if capabilities.contains('a') {
+            Some(Self::Auth)
+        } else {
+            None
+        }
+    }
+}
+
+/// Primary key is used only for certification, signing subkey is created in batch mode.
+pub fn card_info_args(name: &str, email: &str, profile: &KeyProfile) -> String {
+    let primary = profile.algorithm.batch_params("Key");
+    let subkey = profile.algorithm.batch_params("Subkey");
+    let expiry = &profile.expiry;
     format!(
         r"
     %no-protection
-    Key-Type: RSA
-    Key-Length: 4096
+    {primary}
+    Key-Usage: cert
     Name-Real: {name}
     Name-Email: {email}
-    Expire-Date: 0
-    Subkey-Type: RSA
-    Subkey-Length: 4096
-    Subkey-Usage: sign, encrypt, auth
+    Expire-Date: {expiry}
+    {subkey}
+    Subkey-Usage: sign
     %commit
     "
     )
 }
 
+/// Subkeys are listed in creation order: sign, encrypt, auth which matches card slots 1, 2, 3.
 pub fn key_to_card_args() -> String {
     format!(
         r#"{ADMIN_PIN}
 key 1
 keytocard
 1
+key 1
+key 2
 keytocard
 2
+key 2
+key 3
 keytocard
 3
 save"#
     )
 }
 
-#[cfg(target_family = "unix")]
-pub fn set_permissions(dir_path: &PathBuf) {
-    debug!("Setting permissions 700 for gpg temp folder.");
-    let dir_string = dir_path.to_string_lossy();
-    debug!("GPG temp folder set to {dir_string}");
-    let permissions = fs::Permissions::from_mode(0o700);
-    match fs::set_permissions(dir_path, permissions) {
-        Ok(()) => {
-            debug!("Permissions set");
-        }
-        Err(e) => {
-            error!(
-                "Failed to set permissions for GPG TEMP Home! \
-            Location: {dir_string} \n \
-            Error: {0}\n Program will proceed with default permissions.",
-                e.to_string()
-            );
-        }
+/// Card generates signing primary key with encryption and authentication subkeys.
+/// PINs are answered at the prompts they belong to, so the session has to run
+/// without static passphrase. Card is expected in factory state with RSA 2048 slots.
+pub fn card_generate_args(name: &str, email: &str, profile: &KeyProfile) -> String {
+    let expiry = &profile.expiry;
+    let mut args = String::from("admin\n");
+    // changed attribute is written right after first slot answers, which asks for admin PIN
+    let attributes_changed = profile.algorithm != KeyAlgorithm::Rsa2048;
+    if attributes_changed {
+        let key_attr = profile.algorithm.card_key_attr();
+        args.push_str(&format!(
+            "key-attr\n{key_attr}\n{ADMIN_PIN}\n{key_attr}\n{key_attr}\n"
+        ));
+    }
+    // no off-card backup, user PIN is checked before key generation questions
+    args.push_str(&format!(
+        "generate\nn\n{USER_PIN}\n{expiry}\n{name}\n{email}\n\n"
+    ));
+    // otherwise admin PIN is first needed when card generates keys
+    if !attributes_changed {
+        args.push_str(&format!("{ADMIN_PIN}\n"));
     }
+    args.push_str("quit");
+    args
 }
 
+/// gpg converts surname and given name to "Last<<First" card format.
+/// Empty name is not written.
+pub fn cardholder_args(cardholder: &Cardholder) -> String {
+    let Cardholder {
+        surname,
+        given_name,
+        login,
+        language,
+        url,
+    } = cardholder;
+    let mut args = format!("{ADMIN_PIN}\nadmin\n");
+    if !surname.is_empty() {
+        args.push_str(&format!("name\n{surname}\n{given_name}\n"));
+    }
+    args.push_str(&format!("login\n{login}\nlang\n{language}\n"));
+    if let Some(url) = url {
+        args.push_str(&format!("url\n{url}\n"));
+    }
+    args.push_str("quit");
+    args
+}
+
+// creates new directory and fails if it already exists, so it cannot be prepared by someone else
 #[allow(unused_variables)]
-pub fn init_gpg(config: &Config) -> Result<(String, Child), WorkerError> {
-    debug!("Initiating new gpg session.");
-    let mut temp_path = env::temp_dir();
-    temp_path.push("yubikey-provision");
+fn create_gpg_home(path: &Path, skip_permissions: bool) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(target_family = "unix")]
+    if !skip_permissions {
+        builder.mode(0o700);
+    }
+    builder.create(path)
+}
 
+/// Writes file readable only by the worker user.
+pub fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_family = "unix")]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // mode is applied only to new files
     #[cfg(target_family = "unix")]
-    if !config.skip_gpg_permissions {
-        // ignore permissions error, just warn the user and proceed. Default permissions still allow for provisioning to work.
-        set_permissions(&temp_path);
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())
+}
+
+/// Creates unique gpg home for the job and launches its own agent.
+/// Agent of the user running the worker is left untouched.
+fn init_gpg(config: &Config, serial: &str) -> Result<String, WorkerError> {
+    debug!("Initiating new gpg session.");
+    let temp_path = loop {
+        let suffix: String = OsRng
+            .sample_iter(Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        let path = env::temp_dir().join(format!("yubikey-provision-{serial}-{suffix}"));
+        match create_gpg_home(&path, config.skip_gpg_permissions) {
+            Ok(()) => break path,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let temp_path_str = temp_path
+        .to_str()
+        .ok_or_else(|| WorkerError::Gpg("temp directory path is not valid UTF-8".into()))?
+        .to_string();
+    debug!("gpg temporary home: {temp_path_str}");
+
+    let launched = run(
+        "gpgconf",
+        &["--homedir", &temp_path_str, "--launch", "gpg-agent"],
+        None,
+        Step::Agent,
+    )
+    .and_then(|out| {
+        if out.status.success() {
+            Ok(())
+        } else {
+            Err(WorkerError::Gpg(failure_details(&out)))
+        }
+    });
+    if let Err(e) = launched {
+        debug!("Failed to launch gpg agent in {temp_path_str}");
+        let _ = fs::remove_dir_all(&temp_path);
+        return Err(e);
     }
+    debug!("gpg agent alive");
 
-    let temp_path_str = temp_path.to_str().ok_or(WorkerError::Gpg)?;
+    Ok(temp_path_str)
+}
 
-    {
-        let res = Command::new("gpgconf")
-            .args(["--kill", "gpg-agent"])
-            .status()?;
+/// Stops gpg-agent, scdaemon and other daemons started for given home.
+fn kill_gpg(gpg_home: &str) -> Result<(), WorkerError> {
+    let out = run(
+        "gpgconf",
+        &["--homedir", gpg_home, "--kill", "all"],
+        None,
+        Step::Agent,
+    )?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(WorkerError::Gpg(failure_details(&out)))
+    }
+}
 
-        if !res.success() {
-            debug!("Failed to Kill current gpg agent via gpgconf --kill gpg-agent");
-            return Err(WorkerError::Gpg);
+fn overwrite_file(path: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    let length = file.metadata()?.len();
+    io::copy(&mut io::repeat(0).take(length), &mut file)?;
+    file.sync_all()
+}
+
+// overwrites files with zeros, failed entries are logged and the rest is still overwritten
+fn overwrite_dir(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        let result = entry.file_type().and_then(|file_type| {
+            if file_type.is_dir() {
+                overwrite_dir(&entry_path)
+            } else if file_type.is_file() {
+                overwrite_file(&entry_path)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            warn!("Failed to overwrite {}: {e}", entry_path.display());
         }
-        debug!("User gpg agent session killed");
     }
+    Ok(())
+}
+
+// overwrites files with zeros before removing directory, removal is attempted
+// even if overwriting failed, so key material doesn't stay around in full
+fn wipe_dir(path: &Path) -> io::Result<()> {
+    if let Err(e) = overwrite_dir(path) {
+        warn!("Failed to overwrite files in {}: {e}", path.display());
+    }
+    fs::remove_dir_all(path)
+}
 
-    debug!("gpg temporary home: {}", &temp_path_str);
+/// Temporary gpg home with its own agent. Agent and scdaemon are stopped
+/// and the home with all key material is wiped when the session is dropped,
+/// so cleanup runs on errors, panics and cancelled jobs as well.
+pub struct GpgSession {
+    home: String,
+    closed: bool,
+}
 
-    // init temp
-    if Path::new(&temp_path).is_dir() {
-        fs::remove_dir_all(&temp_path)?;
+impl GpgSession {
+    pub fn new(config: &Config, serial: &str) -> Result<Self, WorkerError> {
+        Ok(Self {
+            home: init_gpg(config, serial)?,
+            closed: false,
+        })
     }
-    fs::create_dir_all(&temp_path)?;
-    debug!("gpg home created");
 
-    // init local temp gpg home
+    pub fn home(&self) -> &str {
+        &self.home
+    }
 
-    let gpg_agent = Command::new("gpg-agent")
-        .args(["--homedir", temp_path_str, "--daemon"])
-        .spawn()?;
+    fn cleanup(&mut self) -> Result<(), WorkerError> {
+        self.closed = true;
+        debug!("Clearing gpg session in {}", self.home);
+        let killed = kill_gpg(&self.home);
+        if let Err(e) = wipe_dir(Path::new(&self.home)) {
+            error!("Failed to wipe gpg home {}: {e}", self.home);
+            return Err(WorkerError::GPGSessionEnd);
+        }
+        debug!("Temp home cleared");
+        killed
+    }
 
-    debug!("gpg agent alive");
+    /// Cleans up the session, reporting failures instead of logging them.
+    pub fn close(mut self) -> Result<(), WorkerError> {
+        self.cleanup()
+    }
+}
 
-    Ok((temp_path_str.to_string(), gpg_agent))
+impl Drop for GpgSession {
+    fn drop(&mut self) {
+        if !self.closed {
+            if let Err(e) = self.cleanup() {
+                error!("gpg session cleanup failed: {e}");
+            }
+        }
+    }
 }
 
 pub fn gen_key(
@@ -136,7 +415,8 @@ pub fn gen_key(
     gpg_home: &str,
     full_name: &str,
     email: &str,
-) -> Result<(), WorkerError> {
+    profile: &KeyProfile,
+) -> Result<String, WorkerError> {
     let command_args = [
         "--debug-level",
         gpg_debug_level,
@@ -145,6 +425,8 @@ pub fn gen_key(
         "--batch",
         "--command-fd",
         "0",
+        "--status-fd",
+        "1",
         "--full-gen-key",
     ];
     debug!(
@@ -152,17 +434,107 @@ pub fn gen_key(
         gpg_command,
         command_args.join(" ")
     );
-    let mut child = Command::new(gpg_command)
-        .args(command_args)
-        .stdin(Stdio::piped())
-        .spawn()?;
-    let mut stdin = child.stdin.take().ok_or(WorkerError::Gpg)?;
-    let info_args = card_info_args(full_name, email);
-    std::thread::spawn(move || {
-        let _ = stdin.write_all(info_args.as_bytes());
-    });
-    child.wait()?;
-    Ok(())
+    let info_args = card_info_args(full_name, email, profile);
+    let out = run(
+        gpg_command,
+        &command_args,
+        Some(&info_args),
+        Step::GenerateKey,
+    )?;
+    if !out.status.success() {
+        return Err(WorkerError::Gpg(failure_details(&out)));
+    }
+    let events = parse_status(&String::from_utf8(out.stdout)?);
+    let fingerprint = created_primary(&events)
+        .ok_or_else(|| WorkerError::Gpg("no KEY_CREATED status for primary key".into()))?
+        .to_string();
+    // batch mode supports only one subkey, rest is added afterwards
+    for usage in [SubkeyUsage::Encrypt, SubkeyUsage::Auth] {
+        add_subkey(
+            gpg_command,
+            gpg_debug_level,
+            gpg_home,
+            &fingerprint,
+            &profile.algorithm.subkey_algorithm(usage),
+            usage,
+            &profile.expiry,
+        )?;
+    }
+    Ok(fingerprint)
+}
+
+pub fn add_subkey(
+    gpg_command: &str,
+    gpg_debug_level: &str,
+    gpg_home: &str,
+    fingerprint: &str,
+    algorithm: &str,
+    usage: SubkeyUsage,
+    expiry: &str,
+) -> Result<(), WorkerError> {
+    let command_args = [
+        "--debug-level",
+        gpg_debug_level,
+        "--homedir",
+        gpg_home,
+        "--batch",
+        "--pinentry-mode=loopback",
+        "--passphrase",
+        "",
+        "--quick-add-key",
+        fingerprint,
+        algorithm,
+        usage.as_gpg_usage(),
+        expiry,
+    ];
+    debug!(
+        "Adding {usage:?} subkey via {} with args: {}",
+        gpg_command,
+        command_args.join(" ")
+    );
+    let out = run(gpg_command, &command_args, None, Step::AddSubkey)?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(WorkerError::Gpg(failure_details(&out)))
+    }
+}
+
+// lists public keys matching given user id or fingerprint
+pub fn list_keys(
+    gpg_command: &str,
+    gpg_home: &str,
+    user_id: &str,
+) -> Result<Vec<PublicKey>, WorkerError> {
+    let out = run(
+        gpg_command,
+        &[
+            "--homedir",
+            gpg_home,
+            "--with-colons",
+            "--with-keygrip",
+            "--list-keys",
+            user_id,
+        ],
+        None,
+        Step::ListKeys,
+    )?;
+    if !out.status.success() {
+        return Err(WorkerError::Gpg(failure_details(&out)));
+    }
+    Ok(parse_key_listing(&String::from_utf8(out.stdout)?))
+}
+
+// returns the only key matching given user id
+pub fn find_key(
+    gpg_command: &str,
+    gpg_home: &str,
+    user_id: &str,
+) -> Result<PublicKey, WorkerError> {
+    list_keys(gpg_command, gpg_home, user_id)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| WorkerError::Gpg(format!("no key found for {user_id}")))
 }
 
 pub fn key_to_card(
@@ -191,178 +563,596 @@ pub fn key_to_card(
         gpg_command,
         &command_args.join(" ")
     );
-    let mut child = Command::new(gpg_command)
-        .args(command_args)
-        .env("LANG", "en")
-        .stdin(Stdio::piped())
-        .spawn()?;
-    let mut stdin = child.stdin.take().expect("Failed to get stdin");
-    std::thread::spawn(move || {
-        let input = key_to_card_args();
-        let _ = stdin.write_all(input.as_bytes());
-    });
-    child.wait()?;
+    let out = run(
+        gpg_command,
+        &command_args,
+        Some(&key_to_card_args()),
+        Step::KeyToCard,
+    )?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(WorkerError::Gpg(failure_details(&out)))
+    }
+}
+
+// runs gpg --card-edit session with given commands, with `static_pin` first input line
+// answers every PIN prompt, otherwise PINs are read from input like other answers
+fn card_edit(
+    gpg_command: &str,
+    gpg_debug_level: &str,
+    gpg_home: &str,
+    input: &str,
+    static_pin: bool,
+    step: Step,
+) -> Result<Vec<StatusEvent>, WorkerError> {
+    let mut command_args = vec![
+        "--debug-level",
+        gpg_debug_level,
+        "--homedir",
+        gpg_home,
+        "--command-fd=0",
+        "--status-fd=1",
+    ];
+    if static_pin {
+        command_args.push("--passphrase-fd=0");
+    }
+    command_args.extend([
+        "--batch",
+        "--yes",
+        "--pinentry-mode=loopback",
+        "--no-tty",
+        "--card-edit",
+    ]);
+    debug!(
+        "Editing card via {} with args: {}",
+        gpg_command,
+        &command_args.join(" ")
+    );
+    let out = run(gpg_command, &command_args, Some(input), step)?;
+    if out.status.success() {
+        Ok(parse_status(&String::from_utf8(out.stdout)?))
+    } else {
+        Err(WorkerError::Gpg(failure_details(&out)))
+    }
+}
+
+pub fn gen_key_on_card(
+    gpg_command: &str,
+    gpg_debug_level: &str,
+    gpg_home: &str,
+    full_name: &str,
+    email: &str,
+    profile: &KeyProfile,
+) -> Result<String, WorkerError> {
+    debug!("Generating key on card");
+    let input = card_generate_args(full_name, email, profile);
+    let events = card_edit(
+        gpg_command,
+        gpg_debug_level,
+        gpg_home,
+        &input,
+        false,
+        Step::GenerateOnCard,
+    )?;
+    created_primary(&events)
+        .map(ToString::to_string)
+        .ok_or_else(|| WorkerError::Gpg("no KEY_CREATED status for primary key".into()))
+}
+
+pub fn set_cardholder(
+    gpg_command: &str,
+    gpg_debug_level: &str,
+    gpg_home: &str,
+    cardholder: &Cardholder,
+) -> Result<(), WorkerError> {
+    debug!("Setting cardholder data: {cardholder:?}");
+    card_edit(
+        gpg_command,
+        gpg_debug_level,
+        gpg_home,
+        &cardholder_args(cardholder),
+        true,
+        Step::CardEdit,
+    )?;
     Ok(())
 }
 
+pub fn card_status(gpg_command: &str, gpg_home: &str) -> Result<CardStatus, WorkerError> {
+    let out = run(
+        gpg_command,
+        &["--homedir", gpg_home, "--with-colons", "--card-status"],
+        None,
+        Step::CardStatus,
+    )?;
+    if !out.status.success() {
+        return Err(WorkerError::Gpg(failure_details(&out)));
+    }
+    Ok(parse_card_status(&String::from_utf8(out.stdout)?))
+}
+
+/// Fingerprints of keys stored in each card slot.
+#[derive(Debug, Default)]
+pub struct SubkeyFingerprints {
+    pub sign: String,
+    pub encrypt: String,
+    pub auth: String,
+}
+
+// Keys generated on card use the primary key for signing.
+pub fn subkey_fingerprints(key: &PublicKey) -> Result<SubkeyFingerprints, WorkerError> {
+    let mut fingerprints = SubkeyFingerprints::default();
+    for record in key.records() {
+        let fingerprint = record.fingerprint.clone();
+        match SubkeyUsage::from_capabilities(&record.capabilities) {
+            Some(SubkeyUsage::Sign) => fingerprints.sign = fingerprint,
+            Some(SubkeyUsage::Encrypt) => fingerprints.encrypt = fingerprint,
+            Some(SubkeyUsage::Auth) => fingerprints.auth = fingerprint,
+            None => debug!("Skipping subkey with capabilities: {}", record.capabilities),
+        }
+    }
+    if fingerprints.sign.is_empty()
+        || fingerprints.encrypt.is_empty()
+        || fingerprints.auth.is_empty()
+    {
+        return Err(WorkerError::Gpg(format!(
+            "missing sign, encrypt or auth key: {fingerprints:?}"
+        )));
+    }
+    Ok(fingerprints)
+}
+
 pub fn export_public(
     gpg_command: &str,
     gpg_home: &str,
     email: &str,
 ) -> Result<String, WorkerError> {
-    let out = Command::new(gpg_command)
-        .args(["--homedir", gpg_home, "--armor", "--export", email])
-        .stdout(Stdio::piped())
-        .output()?;
+    let out = run(
+        gpg_command,
+        &["--homedir", gpg_home, "--armor", "--export", email],
+        None,
+        Step::Export,
+    )?;
+    if !out.status.success() {
+        return Err(WorkerError::Gpg(failure_details(&out)));
+    }
     let out_str = String::from_utf8(out.stdout)?;
     Ok(out_str)
 }
 
+/// Encrypts data to public keys stored in given files, returns armored message.
+pub fn encrypt_to_recipients(
+    gpg_command: &str,
+    gpg_home: &str,
+    recipient_files: &[PathBuf],
+    data: &str,
+) -> Result<String, WorkerError> {
+    let mut command_args = vec![
+        "--homedir",
+        gpg_home,
+        "--batch",
+        "--armor",
+        "--trust-model",
+        "always",
+    ];
+    for recipient in recipient_files {
+        command_args.push("--recipient-file");
+        command_args.push(recipient.to_str().ok_or_else(|| {
+            WorkerError::Gpg(format!("invalid recipient path {}", recipient.display()))
+        })?);
+    }
+    command_args.push("--encrypt");
+    let out = run(gpg_command, &command_args, Some(data), Step::Encrypt)?;
+    if !out.status.success() {
+        return Err(WorkerError::Gpg(failure_details(&out)));
+    }
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+// secret subkeys without primary key, keys are generated without passphrase
+pub fn export_secret_subkeys(
+    gpg_command: &str,
+    gpg_home: &str,
+    email: &str,
+) -> Result<String, WorkerError> {
+    let out = run(
+        gpg_command,
+        &[
+            "--homedir",
+            gpg_home,
+            "--batch",
+            "--pinentry-mode=loopback",
+            "--passphrase",
+            "",
+            "--armor",
+            "--export-secret-subkeys",
+            email,
+        ],
+        None,
+        Step::Export,
+    )?;
+    if !out.status.success() {
+        return Err(WorkerError::Gpg(failure_details(&out)));
+    }
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+/// Reads revocation certificate created by gpg during key generation.
+pub fn revocation_certificate(gpg_home: &str, fingerprint: &str) -> Result<String, WorkerError> {
+    let path = Path::new(gpg_home)
+        .join("openpgp-revocs.d")
+        .join(format!("{fingerprint}.rev"));
+    let content = fs::read_to_string(path)?;
+    // gpg prefixes armor header with colon to prevent accidental import
+    let start = content
+        .find(":-----BEGIN PGP PUBLIC KEY BLOCK-----")
+        .ok_or_else(|| {
+            WorkerError::Gpg(format!("invalid revocation certificate of {fingerprint}"))
+        })?;
+    Ok(content[start + 1..].to_string())
+}
+
 pub fn export_ssh(gpg_command: &str, gpg_home: &str, email: &str) -> Result<String, WorkerError> {
-    let out = Command::new(gpg_command)
-        .args(["--homedir", gpg_home, "--export-ssh-key", email])
-        .output()?;
+    let out = run(
+        gpg_command,
+        &["--homedir", gpg_home, "--export-ssh-key", email],
+        None,
+        Step::Export,
+    )?;
+    if !out.status.success() {
+        return Err(WorkerError::Gpg(failure_details(&out)));
+    }
     let out_str = String::from_utf8(out.stdout)?;
     Ok(out_str)
 }
 
-pub fn factory_reset_key() -> Result<(), WorkerError> {
-    let status = Command::new("ykman")
-        .args(["openpgp", "reset", "-f"])
-        .status()?;
-    if status.success() {
+pub fn factory_reset_key(serial: &str) -> Result<(), WorkerError> {
+    let out = run(
+        "ykman",
+        &["--device", serial, "openpgp", "reset", "-f"],
+        None,
+        Step::Reset,
+    )?;
+    if out.status.success() {
         Ok(())
     } else {
-        Err(WorkerError::YubikeyManager)
+        Err(WorkerError::YubikeyManager(failure_details(&out)))
     }
 }
 
-// returns serial number of yubikey if detected
-pub fn check_card() -> Result<String, WorkerError> {
-    let out = Command::new("ykman")
-        .args(["list"])
-        .output()
-        .expect("Failed to call ykman");
+// runs ykman command on key with given serial, PINs are passed through `input`
+// as ykman prompts for omitted ones, arguments are visible to other users
+fn run_ykman(serial: &str, args: &[&str], input: Option<&str>) -> Result<(), WorkerError> {
+    let args = [&["--device", serial], args].concat();
+    let out = run("ykman", &args, input, Step::Ykman)?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(WorkerError::YubikeyManager(failure_details(&out)))
+    }
+}
+
+/// Replaces current card PINs with new ones, reset code is set only if present.
+pub fn set_card_pins(serial: &str, current: &CardPins, new: &CardPins) -> Result<(), WorkerError> {
+    debug!("Changing card PINs");
+    if let Some(reset_code) = &new.reset_code {
+        // new values are confirmed by ykman, so they are entered twice
+        run_ykman(
+            serial,
+            &["openpgp", "access", "change-reset-code"],
+            Some(&format!("{}\n{reset_code}\n{reset_code}\n", current.admin)),
+        )?;
+        debug!("Reset code set");
+    }
+    run_ykman(
+        serial,
+        &["openpgp", "access", "change-pin"],
+        Some(&format!("{}\n{}\n{}\n", current.user, new.user, new.user)),
+    )?;
+    debug!("User PIN changed");
+    run_ykman(
+        serial,
+        &["openpgp", "access", "change-admin-pin"],
+        Some(&format!(
+            "{}\n{}\n{}\n",
+            current.admin, new.admin, new.admin
+        )),
+    )?;
+    debug!("Admin PIN changed");
+    Ok(())
+}
+
+pub fn set_touch_policies(
+    serial: &str,
+    policies: &TouchPolicies,
+    admin_pin: &str,
+) -> Result<(), WorkerError> {
+    for (usage, policy) in [
+        (SubkeyUsage::Sign, policies.sign),
+        (SubkeyUsage::Encrypt, policies.encrypt),
+        (SubkeyUsage::Auth, policies.auth),
+    ] {
+        run_ykman(
+            serial,
+            &[
+                "openpgp",
+                "keys",
+                "set-touch",
+                usage.as_ykman_slot(),
+                policy.as_ykman_policy(),
+                "--force",
+            ],
+            Some(&format!("{admin_pin}\n")),
+        )?;
+        debug!("Touch policy of {usage:?} slot set to {policy:?}");
+    }
+    Ok(())
+}
+
+/// Pins scdaemon of gpg session to reader of key with given serial.
+pub fn pin_reader(gpg_home: &str, serial: &str) -> Result<(), WorkerError> {
+    let reader = reader_name(serial)?;
+    debug!("Using reader {reader} for key ({serial})");
+    // reader names come from PC/SC, shared access lets ykman reach the key
+    // while scdaemon is still running
+    fs::write(
+        Path::new(gpg_home).join("scdaemon.conf"),
+        format!("disable-ccid\npcsc-shared\nreader-port {reader}\n"),
+    )?;
+    // reader options are read only on startup, agent starts scdaemon again on demand
+    let out = run(
+        "gpgconf",
+        &["--homedir", gpg_home, "--kill", "scdaemon"],
+        None,
+        Step::Agent,
+    )?;
     if !out.status.success() {
-        return Err(WorkerError::YubikeyManager);
-    }
-    let out_str =
-        String::from_utf8(out.stdout).expect("Failed to read output from ykman openpgp list");
-    let lines: Vec<String> = out_str
-        .split("\r\n")
-        .filter(|line| !line.is_empty())
-        .map(|line| line.to_string())
-        .collect();
-    let keys_found = lines.len();
-    if keys_found == 0 {
-        return Err(WorkerError::NoKeysFound);
-    }
-    if keys_found != 1 {
-        return Err(WorkerError::MultipleKeysPresent);
-    }
-    let out_split: Vec<&str> = out_str.split_whitespace().collect();
-    for (i, &item) in out_split.iter().enumerate() {
-        if item == "Serial:" {
-            if let Some(serial) = out_split.get(i + 1) {
-                return Ok((*serial).to_string());
-            }
+        debug!("Failed to restart scdaemon");
+    }
+    Ok(())
+}
 
-            return Err(WorkerError::SerialNotFound);
-        }
+fn private_keys_backup_path(gpg_home: &str) -> PathBuf {
+    Path::new(gpg_home).join("private-keys-v1.d.backup")
+}
+
+/// Copies secret keys, keytocard replaces them with card stubs.
+pub fn backup_private_keys(gpg_home: &str) -> Result<(), WorkerError> {
+    let backup_path = private_keys_backup_path(gpg_home);
+    fs::create_dir_all(&backup_path)?;
+    for entry in fs::read_dir(Path::new(gpg_home).join("private-keys-v1.d"))? {
+        let entry = entry?;
+        fs::copy(entry.path(), backup_path.join(entry.file_name()))?;
     }
-    Err(WorkerError::SerialNotFound)
+    debug!("Secret keys copied");
+    Ok(())
 }
 
-#[allow(dead_code)]
-pub fn get_fingerprint() -> Result<String, WorkerError> {
-    let out = Command::new("gpg")
-        .args(["--list-keys"])
-        .env("LANG", "en")
-        .output()
-        .expect("Failed to call gpg");
-    let out_str = String::from_utf8(out.stdout)?;
-    let lines: Vec<String> = out_str
-        .split("\r\n")
-        .filter(|line| !line.is_empty())
-        .map(|line| line.trim().replace(' ', "").to_string())
-        .collect();
-    if let Some(index) = lines.iter().position(|l| l.starts_with("pub")) {
-        return match lines.get(index + 1) {
-            Some(fingerprint) => Ok(fingerprint.to_string()),
-            None => Err(WorkerError::Gpg),
-        };
+/// Overwrites card stubs with secret keys copied by `backup_private_keys`.
+pub fn restore_private_keys(gpg_home: &str) -> Result<(), WorkerError> {
+    let keys_path = Path::new(gpg_home).join("private-keys-v1.d");
+    for entry in fs::read_dir(private_keys_backup_path(gpg_home))? {
+        let entry = entry?;
+        fs::copy(entry.path(), keys_path.join(entry.file_name()))?;
     }
-    Err(WorkerError::Gpg)
+    debug!("Secret keys restored");
+    Ok(())
 }
 
-#[derive(Serialize, Debug)]
-pub struct ProvisioningInfo {
-    pub pgp: String,
-    pub ssh: String,
-    pub serial: String,
+const VERIFICATION_DATA: &str = "yubikey-provision verification\n";
+// SHA-256 digest signed by authentication key, its value does not matter
+const VERIFICATION_DIGEST: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+fn test_sign(
+    gpg_command: &str,
+    gpg_home: &str,
+    fingerprint: &str,
+    pin_file: &str,
+) -> Result<bool, WorkerError> {
+    let local_user = format!("{fingerprint}!");
+    let out = run(
+        gpg_command,
+        &[
+            "--homedir",
+            gpg_home,
+            "--batch",
+            "--pinentry-mode=loopback",
+            "--passphrase-file",
+            pin_file,
+            "--status-fd=2",
+            "--local-user",
+            &local_user,
+            "--output",
+            "-",
+            "--sign",
+        ],
+        Some(VERIFICATION_DATA),
+        Step::TestOperation,
+    )?;
+    let signed = parse_status(&String::from_utf8(out.stderr)?)
+        .iter()
+        .any(|event| matches!(event, StatusEvent::SigCreated { fingerprint: signer } if signer.eq_ignore_ascii_case(fingerprint)));
+    Ok(out.status.success() && signed)
 }
 
-pub async fn provision_key(
-    config: &Config,
-    job: &proto::GetJobResponse,
+fn test_decrypt(
     gpg_command: &str,
-) -> Result<ProvisioningInfo, WorkerError> {
-    let full_name = format!("{} {}", job.first_name, job.last_name);
-    debug!("Provisioning start for: {}", &job.email);
-    let check_duration = Duration::from_secs(config.smartcard_retry_interval);
-    let mut check_interval = interval(check_duration);
-    let mut fail_counter = 0;
-    let serial: String;
-    loop {
-        check_interval.tick().await;
-        match check_card() {
-            Ok(res) => {
-                serial = res;
-                break;
-            }
-            Err(e) => match e {
-                WorkerError::NoKeysFound => {
-                    info!(
-                        "No keys found, retry in {} seconds",
-                        check_duration.as_secs()
-                    );
-                    if fail_counter >= config.smartcard_retries {
-                        return Err(WorkerError::NoKeysFound);
-                    }
-                    fail_counter += 1;
-                }
-                _ => return Err(e),
-            },
-        }
+    gpg_home: &str,
+    fingerprint: &str,
+    pin_file: &str,
+) -> Result<bool, WorkerError> {
+    let recipient = format!("{fingerprint}!");
+    let out = run(
+        gpg_command,
+        &[
+            "--homedir",
+            gpg_home,
+            "--batch",
+            "--armor",
+            "--trust-model",
+            "always",
+            "--recipient",
+            &recipient,
+            "--encrypt",
+        ],
+        Some(VERIFICATION_DATA),
+        Step::TestOperation,
+    )?;
+    if !out.status.success() {
+        return Err(WorkerError::Gpg(failure_details(&out)));
     }
-    debug!("Key with serial ({serial}) found");
-    let (gpg_home, mut gpg_process) = init_gpg(config)?;
-    debug!("Temporary GPG session crated");
-    debug!("Resetting card to factory");
-    factory_reset_key()?;
-    debug!("OpenPGP Key app restored to factory.");
-    gen_key(
+    let out = run(
         gpg_command,
-        &config.gpg_debug_level,
-        &gpg_home,
-        &full_name,
-        &job.email,
+        &[
+            "--homedir",
+            gpg_home,
+            "--batch",
+            "--pinentry-mode=loopback",
+            "--passphrase-file",
+            pin_file,
+            "--status-fd=2",
+            "--decrypt",
+        ],
+        Some(&String::from_utf8(out.stdout)?),
+        Step::TestOperation,
+    )?;
+    let decrypted =
+        parse_status(&String::from_utf8(out.stderr)?).contains(&StatusEvent::DecryptionOkay);
+    Ok(out.status.success() && decrypted && out.stdout == VERIFICATION_DATA.as_bytes())
+}
+
+// returns keygrip of (sub)key with given fingerprint
+fn keygrip(gpg_command: &str, gpg_home: &str, fingerprint: &str) -> Result<String, WorkerError> {
+    list_keys(gpg_command, gpg_home, &format!("{fingerprint}!"))?
+        .iter()
+        .flat_map(PublicKey::records)
+        .find(|record| record.fingerprint == fingerprint)
+        .and_then(|record| record.keygrip.clone())
+        .ok_or_else(|| WorkerError::Gpg(format!("no keygrip listed for {fingerprint}")))
+}
+
+// gpg has no command using authentication key, so digest is signed through the agent
+fn test_auth(
+    gpg_command: &str,
+    gpg_home: &str,
+    fingerprint: &str,
+    user_pin: &str,
+) -> Result<bool, WorkerError> {
+    let keygrip = keygrip(gpg_command, gpg_home, fingerprint)?;
+    let input = format!(
+        "/let pin {user_pin}\n/definq PASSPHRASE pin\nOPTION pinentry-mode=loopback\n\
+         SIGKEY {keygrip}\nSETHASH --hash=sha256 {VERIFICATION_DIGEST}\nPKSIGN\n/bye\n"
+    );
+    let out = run(
+        "gpg-connect-agent",
+        &["--homedir", gpg_home],
+        Some(&input),
+        Step::TestOperation,
     )?;
-    debug!("OpenPGP key for {} created", &job.email);
-    let pgp = export_public(gpg_command, &gpg_home, &job.email)?;
-    let ssh = export_ssh(gpg_command, &gpg_home, &job.email)?;
-    key_to_card(gpg_command, &config.gpg_debug_level, &gpg_home, &job.email)?;
-    debug!("Subkeys saved in yubikey");
-    // cleanup after provisioning
-    debug!("Clearing gpg process and home");
-    if gpg_process.kill().is_err() {
-        return Err(WorkerError::GPGSessionEnd);
-    }
-    debug!("gpg session killed");
-    if fs::remove_dir_all(&gpg_home).is_err() {
-        return Err(WorkerError::GPGSessionEnd);
-    }
-    debug!("Temp home cleared");
-    info!("Yubikey openpgp provisioning completed.");
-    Ok(ProvisioningInfo { pgp, ssh, serial })
+    let out_str = String::from_utf8(out.stdout)?;
+    Ok(out.status.success()
+        && out_str.lines().any(|line| line.starts_with("D "))
+        && !out_str.lines().any(|line| line.starts_with("ERR")))
+}
+
+/// Runs test operation with card key of given usage.
+pub fn test_key(
+    gpg_command: &str,
+    gpg_home: &str,
+    usage: SubkeyUsage,
+    fingerprint: &str,
+    user_pin: &str,
+) -> Result<bool, WorkerError> {
+    if usage == SubkeyUsage::Auth {
+        return test_auth(gpg_command, gpg_home, fingerprint, user_pin);
+    }
+    // PIN is read by gpg from a private file, command line is visible to other users
+    let path = Path::new(gpg_home).join("user-pin");
+    write_private(&path, user_pin)?;
+    let pin_file = path
+        .to_str()
+        .ok_or_else(|| WorkerError::Gpg("gpg home path is not valid UTF-8".into()))?;
+    let result = match usage {
+        SubkeyUsage::Sign => test_sign(gpg_command, gpg_home, fingerprint, pin_file),
+        _ => test_decrypt(gpg_command, gpg_home, fingerprint, pin_file),
+    };
+    fs::remove_file(&path)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(algorithm: KeyAlgorithm) -> KeyProfile {
+        KeyProfile {
+            algorithm,
+            expiry: "2y".into(),
+        }
+    }
+
+    #[test]
+    fn test_cardholder_args() {
+        let mut cardholder = Cardholder {
+            surname: "Kowalski".into(),
+            given_name: "Jan".into(),
+            login: "jan@example.com".into(),
+            language: "plen".into(),
+            url: Some("https://keys.example.com/jan@example.com".into()),
+        };
+        assert_eq!(
+            cardholder_args(&cardholder),
+            "12345678\nadmin\nname\nKowalski\nJan\nlogin\njan@example.com\nlang\nplen\n\
+             url\nhttps://keys.example.com/jan@example.com\nquit"
+        );
+        cardholder.surname.clear();
+        cardholder.given_name.clear();
+        cardholder.url = None;
+        assert_eq!(
+            cardholder_args(&cardholder),
+            "12345678\nadmin\nlogin\njan@example.com\nlang\nplen\nquit"
+        );
+    }
+
+    #[test]
+    fn test_card_generate_args() {
+        assert_eq!(
+            card_generate_args(
+                "Jan Kowalski",
+                "jan@example.com",
+                &profile(KeyAlgorithm::Ed25519)
+            ),
+            "admin\nkey-attr\n2\n1\n12345678\n2\n1\n2\n1\n\
+             generate\nn\n123456\n2y\nJan Kowalski\njan@example.com\n\nquit"
+        );
+        assert_eq!(
+            card_generate_args(
+                "Jan Kowalski",
+                "jan@example.com",
+                &profile(KeyAlgorithm::Rsa4096)
+            ),
+            "admin\nkey-attr\n1\n4096\n12345678\n1\n4096\n1\n4096\n\
+             generate\nn\n123456\n2y\nJan Kowalski\njan@example.com\n\nquit"
+        );
+        // factory attributes are kept, admin PIN is asked during generation
+        assert_eq!(
+            card_generate_args(
+                "Jan Kowalski",
+                "jan@example.com",
+                &profile(KeyAlgorithm::Rsa2048)
+            ),
+            "admin\ngenerate\nn\n123456\n2y\nJan Kowalski\njan@example.com\n\n12345678\nquit"
+        );
+    }
+
+    #[test]
+    fn test_wipe_dir() {
+        let dir = env::temp_dir().join(format!("yubikey-provision-wipe-{}", std::process::id()));
+        fs::create_dir_all(dir.join("private-keys-v1.d")).unwrap();
+        fs::write(dir.join("private-keys-v1.d").join("key.key"), "secret").unwrap();
+        fs::write(dir.join("pubring.kbx"), "public").unwrap();
+        wipe_dir(&dir).unwrap();
+        assert!(!dir.exists());
+    }
 }
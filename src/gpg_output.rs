@@ -0,0 +1,379 @@
+use std::fmt;
+
+use chrono::DateTime;
+
+// decodes \xHH escapes used by gpg in colon delimited fields
+fn unescape(field: &str) -> String {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'\\' && tail.first() == Some(&b'x') {
+            if let Some(decoded) = tail
+                .get(1..3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                bytes.push(decoded);
+                rest = &tail[3..];
+                continue;
+            }
+        }
+        bytes.push(byte);
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// returns unescaped field, empty fields are None
+fn field(fields: &[&str], index: usize) -> Option<String> {
+    fields
+        .get(index)
+        .filter(|value| !value.is_empty())
+        .map(|value| unescape(value))
+}
+
+/// Card state read with `gpg --card-status --with-colons`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CardStatus {
+    pub serial: Option<String>,
+    pub given_name: Option<String>,
+    pub surname: Option<String>,
+    pub login: Option<String>,
+    pub signature_counter: u64,
+    /// Fingerprints of keys in signature, encryption and authentication slots
+    pub fingerprints: [Option<String>; 3],
+}
+
+impl CardStatus {
+    pub fn has_keys(&self) -> bool {
+        self.fingerprints.iter().any(Option::is_some)
+    }
+
+    pub fn cardholder(&self) -> String {
+        [&self.given_name, &self.surname]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+pub fn parse_card_status(output: &str) -> CardStatus {
+    let mut status = CardStatus::default();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[0] {
+            "serial" => status.serial = field(&fields, 1),
+            // gpg splits "Surname<<Given" card format into given name and surname
+            "name" => {
+                status.given_name = field(&fields, 1);
+                status.surname = field(&fields, 2);
+            }
+            "login" => status.login = field(&fields, 1),
+            "sigcount" => {
+                status.signature_counter = field(&fields, 1)
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or_default();
+            }
+            "fpr" => {
+                for (slot, fingerprint) in status.fingerprints.iter_mut().enumerate() {
+                    *fingerprint = field(&fields, slot + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    status
+}
+
+/// Primary key or subkey record of `gpg --with-colons` key listing.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyRecord {
+    pub fingerprint: String,
+    pub key_id: String,
+    /// OpenPGP public key algorithm id, e.g. 1 for RSA, 18 for ECDH, 22 for EdDSA
+    pub algorithm: u8,
+    pub length: u32,
+    pub curve: Option<String>,
+    /// Unix timestamps
+    pub created: Option<u64>,
+    pub expires: Option<u64>,
+    pub capabilities: String,
+    /// Listed only with `--with-keygrip`
+    pub keygrip: Option<String>,
+}
+
+impl KeyRecord {
+    fn from_fields(fields: &[&str]) -> Self {
+        let number = |index| field(fields, index).and_then(|value| value.parse().ok());
+        Self {
+            length: number(2).unwrap_or_default(),
+            algorithm: field(fields, 3)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_default(),
+            key_id: field(fields, 4).unwrap_or_default(),
+            created: number(5).map(u64::from),
+            expires: number(6).map(u64::from),
+            capabilities: field(fields, 11).unwrap_or_default(),
+            curve: field(fields, 16),
+            ..Default::default()
+        }
+    }
+
+    /// Algorithm name in gpg notation, e.g. rsa4096 or ed25519.
+    pub fn algorithm_name(&self) -> String {
+        match (self.algorithm, &self.curve) {
+            (1..=3, _) => format!("rsa{}", self.length),
+            (16 | 20, _) => format!("elg{}", self.length),
+            (17, _) => format!("dsa{}", self.length),
+            (_, Some(curve)) => curve.clone(),
+            (algorithm, None) => format!("unknown{algorithm}"),
+        }
+    }
+}
+
+// formats unix timestamp as RFC 3339 date
+fn format_timestamp(timestamp: Option<u64>) -> Option<String> {
+    timestamp
+        .and_then(|timestamp| i64::try_from(timestamp).ok())
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .map(|date| date.to_rfc3339())
+}
+
+impl fmt::Display for KeyRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({}) [{}] created: {}, expires: {}",
+            self.algorithm_name(),
+            self.key_id,
+            self.fingerprint,
+            self.capabilities,
+            format_timestamp(self.created)
+                .as_deref()
+                .unwrap_or("unknown"),
+            format_timestamp(self.expires).as_deref().unwrap_or("never"),
+        )
+    }
+}
+
+/// Public or secret key with its user ids and subkeys.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    pub primary: KeyRecord,
+    pub user_ids: Vec<String>,
+    pub subkeys: Vec<KeyRecord>,
+}
+
+impl PublicKey {
+    /// Primary key followed by subkeys.
+    pub fn records(&self) -> impl Iterator<Item = &KeyRecord> {
+        std::iter::once(&self.primary).chain(&self.subkeys)
+    }
+
+    fn last_record(&mut self) -> &mut KeyRecord {
+        match self.subkeys.last_mut() {
+            Some(subkey) => subkey,
+            None => &mut self.primary,
+        }
+    }
+}
+
+/// Parses `gpg --with-colons --list-keys` or `--list-secret-keys` output.
+pub fn parse_key_listing(output: &str) -> Vec<PublicKey> {
+    let mut keys: Vec<PublicKey> = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match (fields[0], keys.last_mut()) {
+            ("pub" | "sec", _) => keys.push(PublicKey {
+                primary: KeyRecord::from_fields(&fields),
+                ..Default::default()
+            }),
+            ("sub" | "ssb", Some(key)) => key.subkeys.push(KeyRecord::from_fields(&fields)),
+            ("uid", Some(key)) => key.user_ids.extend(field(&fields, 9)),
+            // fpr and grp records follow the record of key they describe
+            ("fpr", Some(key)) => {
+                key.last_record().fingerprint = field(&fields, 9).unwrap_or_default()
+            }
+            ("grp", Some(key)) => key.last_record().keygrip = field(&fields, 9),
+            _ => {}
+        }
+    }
+    keys
+}
+
+/// Status line written by gpg to `--status-fd`, other keywords are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatusEvent {
+    /// Key type is P for primary key, S for subkey and B for both
+    KeyCreated {
+        kind: String,
+        fingerprint: String,
+    },
+    SigCreated {
+        fingerprint: String,
+    },
+    DecryptionOkay,
+    Failure {
+        location: String,
+        code: String,
+    },
+    Error {
+        location: String,
+        code: String,
+    },
+}
+
+pub fn parse_status(output: &str) -> Vec<StatusEvent> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] "))
+        .filter_map(|line| {
+            let args: Vec<&str> = line.split_whitespace().collect();
+            let arg = |index: usize| args.get(index).map(ToString::to_string);
+            match *args.first()? {
+                "KEY_CREATED" => Some(StatusEvent::KeyCreated {
+                    kind: arg(1)?,
+                    fingerprint: arg(2)?,
+                }),
+                "SIG_CREATED" => Some(StatusEvent::SigCreated {
+                    fingerprint: arg(6)?,
+                }),
+                "DECRYPTION_OKAY" => Some(StatusEvent::DecryptionOkay),
+                "FAILURE" => Some(StatusEvent::Failure {
+                    location: arg(1)?,
+                    code: arg(2)?,
+                }),
+                "ERROR" => Some(StatusEvent::Error {
+                    location: arg(1)?,
+                    code: arg(2)?,
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Fingerprint of primary key reported by KEY_CREATED status.
+pub fn created_primary(events: &[StatusEvent]) -> Option<&str> {
+    events.iter().find_map(|event| match event {
+        StatusEvent::KeyCreated { kind, fingerprint } if kind == "P" || kind == "B" => {
+            Some(fingerprint.as_str())
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_card_status_provisioned() {
+        let status = parse_card_status(include_str!(
+            "../tests/fixtures/gpg/card_status_provisioned.txt"
+        ));
+        assert_eq!(status.serial.as_deref(), Some("12345678"));
+        assert_eq!(status.cardholder(), "Jan Kowalski");
+        assert_eq!(status.login.as_deref(), Some("jan@example.com"));
+        assert_eq!(status.signature_counter, 17);
+        assert_eq!(
+            status.fingerprints[0].as_deref(),
+            Some("4A1E6E4B9D3C2A1F0E5D7C8B9A0F1E2D3C4B5A69")
+        );
+        assert!(status.has_keys());
+    }
+
+    #[test]
+    fn test_parse_card_status_empty() {
+        let status = parse_card_status(include_str!("../tests/fixtures/gpg/card_status_empty.txt"));
+        assert_eq!(status.serial.as_deref(), Some("23456789"));
+        assert_eq!(status.cardholder(), "");
+        assert_eq!(status.signature_counter, 0);
+        assert!(!status.has_keys());
+    }
+
+    #[test]
+    fn test_parse_key_listing_rsa() {
+        let keys = parse_key_listing(include_str!("../tests/fixtures/gpg/list_keys_rsa.txt"));
+        assert_eq!(keys.len(), 1);
+        let key = &keys[0];
+        assert_eq!(
+            key.primary.fingerprint,
+            "0F3C2B7A4E6D8C1B9A5F7E3D2C1B0A9F8E7D6C5B"
+        );
+        assert_eq!(key.primary.key_id, "2C1B0A9F8E7D6C5B");
+        assert_eq!(key.primary.algorithm, 1);
+        assert_eq!(key.primary.length, 4096);
+        assert_eq!(key.primary.created, Some(1_700_000_000));
+        assert_eq!(key.primary.expires, None);
+        assert_eq!(key.primary.capabilities, "cSEA");
+        assert_eq!(
+            key.user_ids,
+            vec!["Jan Kowalski <jan@example.com>".to_string()]
+        );
+        let capabilities: Vec<_> = key
+            .subkeys
+            .iter()
+            .map(|s| s.capabilities.as_str())
+            .collect();
+        assert_eq!(capabilities, ["s", "e", "a"]);
+        assert_eq!(
+            key.subkeys[2].keygrip.as_deref(),
+            Some("C3D4E5F60718293A4B5C6D7E8F90A1B2C3D4E5F6")
+        );
+        assert_eq!(key.records().count(), 4);
+        assert_eq!(
+            key.subkeys[0].to_string(),
+            "rsa4096 1A2B3C4D5E6F7A8B (4A1E6E4B9D3C2A1F0E5D7C8B1A2B3C4D5E6F7A8B) [s] \
+             created: 2023-11-14T22:13:20+00:00, expires: never"
+        );
+    }
+
+    #[test]
+    fn test_parse_key_listing_ed25519() {
+        let keys = parse_key_listing(include_str!("../tests/fixtures/gpg/list_keys_ed25519.txt"));
+        let key = &keys[0];
+        assert_eq!(key.primary.algorithm, 22);
+        assert_eq!(key.primary.curve.as_deref(), Some("ed25519"));
+        assert_eq!(key.primary.expires, Some(1_731_536_000));
+        assert_eq!(key.subkeys[0].algorithm, 18);
+        assert_eq!(key.subkeys[0].curve.as_deref(), Some("cv25519"));
+        assert_eq!(key.subkeys[0].algorithm_name(), "cv25519");
+        assert_eq!(
+            key.subkeys[0].fingerprint,
+            "7E1D2C3B4A5F6E7D8C9BA0B1C2D3E4F5A6B7C8D9"
+        );
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let events = parse_status(include_str!("../tests/fixtures/gpg/status_gen_key.txt"));
+        assert_eq!(
+            created_primary(&events),
+            Some("0F3C2B7A4E6D8C1B9A5F7E3D2C1B0A9F8E7D6C5B")
+        );
+        assert!(events.contains(&StatusEvent::Failure {
+            location: "gpg-exit".into(),
+            code: "33554433".into()
+        }));
+        assert_eq!(
+            parse_status(
+                "[GNUPG:] SIG_CREATED S 1 8 00 1700000000 ABCDEF\n[GNUPG:] DECRYPTION_OKAY\n"
+            ),
+            vec![
+                StatusEvent::SigCreated {
+                    fingerprint: "ABCDEF".into()
+                },
+                StatusEvent::DecryptionOkay
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("https\\x3a//example.com"), "https://example.com");
+        assert_eq!(unescape("plain\\xZZ"), "plain\\xZZ");
+    }
+}
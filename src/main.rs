@@ -1,11 +1,14 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
+use card::YkmanBackend;
 use config::get_config;
 use error::WorkerError;
-use gpg::provision_key;
 use log::{debug, error, info};
+use process::{set_timeouts, Timeouts};
 use proto::{worker_service_client::WorkerServiceClient, JobStatus, Worker};
-use tokio::time::interval;
+use provision::provision_key;
+use registry::SharedRegistry;
+use tokio::{runtime::Handle, sync::Semaphore, task, time::interval};
 use tonic::{
     metadata::MetadataValue,
     transport::{Certificate, ClientTlsConfig, Endpoint},
@@ -15,10 +18,21 @@ use which::which;
 
 use crate::gpg::get_gpg_command;
 
+mod card;
+mod cardholder;
 mod config;
 mod error;
 mod gpg;
+mod gpg_output;
 mod logging;
+mod pin;
+mod process;
+mod provision;
+mod registry;
+#[cfg(test)]
+mod simulated_card;
+mod stage;
+mod ykman;
 
 #[allow(non_snake_case)]
 mod proto {
@@ -40,6 +54,7 @@ async fn main() -> Result<(), WorkerError> {
     logging::init(&config.log_level, &None).expect("Failed to init logging, check logging config");
     debug!("Logging initialized.");
     debug!("Current config: {:?}", &config);
+    set_timeouts(Timeouts::from(&config));
     // Check required binaries
     let gpg_command = get_gpg_command();
     debug!("gpg command: {}", &gpg_command);
@@ -47,6 +62,7 @@ async fn main() -> Result<(), WorkerError> {
         panic!("'ykman' not found!");
     }
     debug!("ykman present");
+    let backend = Arc::new(YkmanBackend::new(gpg_command, &config.gpg_debug_level));
     // Make grpc client
     let mut url = config.url.clone();
     if config.grpc_ca.is_some() {
@@ -94,8 +110,16 @@ async fn main() -> Result<(), WorkerError> {
     // worker loop
     let period = Duration::from_secs(2);
     let mut client_interval = interval(period);
+    // keys used by running jobs and provisioned by this worker
+    let registry = SharedRegistry::default();
+    let job_slots = Arc::new(Semaphore::new(config.max_parallel_jobs));
     loop {
         client_interval.tick().await;
+        // ask for another job only when there is a free slot
+        let permit = Arc::clone(&job_slots)
+            .acquire_owned()
+            .await
+            .expect("Job slots semaphore closed");
         // attempt to get job
         let worker_request = tonic::Request::new(Worker {
             id: config.worker_id.clone(),
@@ -103,37 +127,67 @@ async fn main() -> Result<(), WorkerError> {
         if let Ok(job_response) = client.get_job(worker_request).await {
             let job_data = job_response.into_inner();
             debug!("Job received: {job_data:?}");
-            match provision_key(&config, &job_data, gpg_command).await {
-                Ok(key_info) => {
-                    let job_status: JobStatus = JobStatus {
-                        id: config.worker_id.clone(),
-                        job_id: job_data.job_id,
-                        success: true,
-                        public_key: key_info.pgp,
-                        ssh_key: key_info.ssh,
-                        yubikey_serial: key_info.serial,
-                        error: String::new(),
-                    };
-                    let request = tonic::Request::new(job_status);
-                    let _ = client.set_job_done(request).await;
+            let config = config.clone();
+            let registry = Arc::clone(&registry);
+            let backend = Arc::clone(&backend);
+            let mut client = client.clone();
+            task::spawn(async move {
+                let worker_id = config.worker_id.clone();
+                let job_id = job_data.job_id;
+                // provisioning blocks on gpg and ykman processes, run it outside of async workers
+                let job = task::spawn_blocking(move || {
+                    Handle::current().block_on(provision_key(
+                        &config,
+                        &job_data,
+                        gpg_command,
+                        backend.as_ref(),
+                        &registry,
+                    ))
+                });
+                // panicked job has to be reported too, otherwise it stays pending on the server
+                let result = job
+                    .await
+                    .unwrap_or_else(|e| Err(WorkerError::JobAborted(e.to_string())));
+                match result {
+                    Ok(key_info) => {
+                        // job status holds a single serial, backup key is only logged
+                        if let Some(backup_serial) = &key_info.backup_serial {
+                            info!(
+                                "Job {job_id}: key {} provisioned with backup key {backup_serial}",
+                                key_info.serial
+                            );
+                        }
+                        let job_status: JobStatus = JobStatus {
+                            id: worker_id,
+                            job_id,
+                            success: true,
+                            public_key: key_info.pgp,
+                            ssh_key: key_info.ssh,
+                            yubikey_serial: key_info.serial,
+                            error: String::new(),
+                        };
+                        let request = tonic::Request::new(job_status);
+                        let _ = client.set_job_done(request).await;
+                    }
+                    Err(err) => {
+                        debug!("Provisioning FAILED: {}", err.to_string());
+                        let job_status: JobStatus = JobStatus {
+                            id: worker_id,
+                            job_id,
+                            success: false,
+                            public_key: String::new(),
+                            ssh_key: String::new(),
+                            yubikey_serial: String::new(),
+                            error: err.to_string(),
+                        };
+                        let request = tonic::Request::new(job_status);
+                        let _ = client.set_job_done(request).await;
+                        debug!("Job result sent");
+                        error!("Job failed! Result sent");
+                    }
                 }
-                Err(err) => {
-                    debug!("Provisioning FAILED: {}", err.to_string());
-                    let job_status: JobStatus = JobStatus {
-                        id: config.worker_id.clone(),
-                        job_id: job_data.job_id,
-                        success: false,
-                        public_key: String::new(),
-                        ssh_key: String::new(),
-                        yubikey_serial: String::new(),
-                        error: err.to_string(),
-                    };
-                    let request = tonic::Request::new(job_status);
-                    let _ = client.set_job_done(request).await;
-                    debug!("Job result sent");
-                    error!("Job failed! Result sent");
-                }
-            }
+                drop(permit);
+            });
         }
     }
 }
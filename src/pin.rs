@@ -0,0 +1,85 @@
+use clap::ValueEnum;
+use rand::{distributions::Uniform, rngs::OsRng, Rng};
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    gpg::{ADMIN_PIN, USER_PIN},
+};
+
+const DIGITS: &[u8] = b"0123456789";
+// ambiguous characters (0/O, 1/l/I) are left out so PINs can be retyped by hand
+const ALPHANUMERIC: &[u8] = b"23456789abcdefghijkmnopqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ";
+
+/// Minimal lengths accepted by OpenPGP card.
+pub const MIN_USER_PIN_LENGTH: usize = 6;
+pub const MIN_ADMIN_PIN_LENGTH: usize = 8;
+pub const MIN_RESET_CODE_LENGTH: usize = 8;
+
+/// Characters used in generated PINs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PinCharset {
+    #[default]
+    Numeric,
+    Alphanumeric,
+}
+
+impl PinCharset {
+    fn chars(self) -> &'static [u8] {
+        match self {
+            Self::Numeric => DIGITS,
+            Self::Alphanumeric => ALPHANUMERIC,
+        }
+    }
+}
+
+fn random_pin(length: usize, charset: PinCharset) -> String {
+    let chars = charset.chars();
+    OsRng
+        .sample_iter(Uniform::from(0..chars.len()))
+        .take(length)
+        .map(|index| chars[index] as char)
+        .collect()
+}
+
+/// PINs protecting OpenPGP application of the card.
+#[derive(Debug, Clone)]
+pub struct CardPins {
+    pub user: String,
+    pub admin: String,
+    pub reset_code: Option<String>,
+}
+
+impl Default for CardPins {
+    /// Factory defaults, reset code is not set after reset.
+    fn default() -> Self {
+        Self {
+            user: USER_PIN.into(),
+            admin: ADMIN_PIN.into(),
+            reset_code: None,
+        }
+    }
+}
+
+impl CardPins {
+    pub fn random(config: &Config) -> Self {
+        Self {
+            user: random_pin(config.user_pin_length, config.pin_charset),
+            admin: random_pin(config.admin_pin_length, config.pin_charset),
+            reset_code: Some(random_pin(config.reset_code_length, config.pin_charset)),
+        }
+    }
+
+    /// Plain text summary handed over to the key owner.
+    pub fn envelope(&self, serials: &str) -> String {
+        let mut envelope = format!(
+            "YubiKey serial: {serials}\nUser PIN: {}\nAdmin PIN: {}\n",
+            self.user, self.admin
+        );
+        if let Some(reset_code) = &self.reset_code {
+            envelope.push_str(&format!("Reset code: {reset_code}\n"));
+        }
+        envelope
+    }
+}
@@ -0,0 +1,1065 @@
+use std::{collections::HashSet, fs, slice, time::Duration};
+
+use log::{debug, info, warn};
+use tokio::time::interval;
+
+use crate::card::CardBackend;
+use crate::cardholder::Cardholder;
+use crate::config::{Config, KeyGenerationMode, ResetPolicy, TouchPolicy};
+use crate::error::WorkerError;
+use crate::gpg::{
+    backup_private_keys, encrypt_to_recipients, export_public, export_secret_subkeys, export_ssh,
+    find_key, gen_key, restore_private_keys, revocation_certificate, subkey_fingerprints,
+    write_private, GpgSession, KeyProfile, SubkeyFingerprints, SubkeyUsage, TouchPolicies,
+    ADMIN_PIN, USER_PIN,
+};
+use crate::gpg_output::{CardStatus, PublicKey};
+use crate::pin::CardPins;
+use crate::proto;
+use crate::registry::{CardClaim, SharedRegistry};
+use crate::stage::{Progress, Stage};
+use crate::ykman::{FirmwareVersion, YubiKeyDevice};
+
+// firmware range generating weak RSA keys on card (ROCA, YSA-2017-01)
+const ROCA_FIRMWARE: (FirmwareVersion, FirmwareVersion) =
+    (FirmwareVersion::new(4, 2, 6), FirmwareVersion::new(4, 3, 4));
+
+/// Checks if key can hold keys described by profile, runs before anything on the card is wiped.
+pub fn preflight(
+    device: &YubiKeyDevice,
+    profile: &KeyProfile,
+    mode: KeyGenerationMode,
+    touch: &TouchPolicies,
+) -> Result<(), WorkerError> {
+    if !device.usb_applications.iter().any(|app| app == "OpenPGP") {
+        return Err(WorkerError::OpenPgpDisabled(device.serial.clone()));
+    }
+    let Some(firmware) = device.firmware else {
+        warn!(
+            "Firmware version of key ({}) is unknown, skipping firmware checks",
+            device.serial
+        );
+        return Ok(());
+    };
+    let unsupported = |requirement: String| WorkerError::UnsupportedFirmware {
+        serial: device.serial.clone(),
+        firmware: firmware.to_string(),
+        requirement,
+    };
+    let required = profile.algorithm.min_firmware();
+    if firmware < required {
+        return Err(unsupported(format!(
+            "{:?} keys, firmware {required} or newer is required",
+            profile.algorithm
+        )));
+    }
+    if mode == KeyGenerationMode::Card
+        && profile.algorithm.rsa_length().is_some()
+        && (ROCA_FIRMWARE.0..=ROCA_FIRMWARE.1).contains(&firmware)
+    {
+        return Err(unsupported(
+            "RSA keys generated on card, firmware is affected by ROCA".into(),
+        ));
+    }
+    for (usage, policy) in [
+        (SubkeyUsage::Sign, touch.sign),
+        (SubkeyUsage::Encrypt, touch.encrypt),
+        (SubkeyUsage::Auth, touch.auth),
+    ] {
+        if let Some(required) = policy
+            .min_firmware()
+            .filter(|required| firmware < *required)
+        {
+            return Err(unsupported(format!(
+                "{policy:?} touch policy of {usage:?} slot, firmware {required} or newer is required"
+            )));
+        }
+    }
+    debug!("Key ({}) passed preflight checks", device.serial);
+    Ok(())
+}
+
+/// Decides if key can be wiped according to its current state, runs before factory reset.
+pub fn check_reset_policy(
+    status: &CardStatus,
+    policy: ResetPolicy,
+    serial: &str,
+    email: &str,
+) -> Result<(), WorkerError> {
+    if !status.has_keys() {
+        return Ok(());
+    }
+    debug!("Key ({serial}) already holds keys: {status:?}");
+    let allowed = match policy {
+        ResetPolicy::Refuse => false,
+        ResetPolicy::SameUser => status.login.as_deref() == Some(email),
+        ResetPolicy::Allow => true,
+    };
+    if allowed {
+        info!(
+            "Key ({serial}) of {} already holds keys, wiping it according to {policy:?} policy",
+            status.cardholder()
+        );
+        Ok(())
+    } else {
+        Err(WorkerError::KeyInUse {
+            serial: serial.to_string(),
+            cardholder: status.cardholder(),
+            signature_counter: status.signature_counter,
+        })
+    }
+}
+
+// encrypts data to escrow recipients and saves it in escrow directory
+fn save_escrow(
+    config: &Config,
+    gpg_command: &str,
+    gpg_home: &str,
+    file_name: &str,
+    data: &str,
+) -> Result<(), WorkerError> {
+    let encrypted =
+        encrypt_to_recipients(gpg_command, gpg_home, &config.escrow_recipient_keys, data)?;
+    if let Some(escrow_dir) = &config.escrow_dir {
+        let path = escrow_dir.join(file_name);
+        fs::write(&path, encrypted)?;
+        info!("Escrow file saved to {}", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct ProvisioningInfo {
+    pub pgp: String,
+    pub ssh: String,
+    pub serial: String,
+    /// Serial of backup key holding the same subkeys
+    pub backup_serial: Option<String>,
+}
+
+// job status has no place for key metadata, keep it in worker log for auditing
+// TODO: report key metadata with job status once worker proto carries it
+fn log_key_metadata(key: &PublicKey) {
+    info!("Issued key for {}", key.user_ids.join(", "));
+    for record in key.records() {
+        info!("  {record}");
+    }
+}
+
+// waits until key matching selection rules of `select_card` is connected and claims it
+async fn wait_for_card(
+    config: &Config,
+    backend: &dyn CardBackend,
+    registry: &SharedRegistry,
+    requested: Option<&str>,
+    retries: u64,
+    skipped: &HashSet<String>,
+) -> Result<CardClaim, WorkerError> {
+    let check_duration = Duration::from_secs(config.smartcard_retry_interval);
+    let mut check_interval = interval(check_duration);
+    let mut fail_counter = 0;
+    loop {
+        check_interval.tick().await;
+        let serials = backend.list_serials()?;
+        debug!("Connected keys: {serials:?}");
+        match CardClaim::claim(registry, &serials, requested, skipped) {
+            Ok(claim) => return Ok(claim),
+            Err(WorkerError::NoKeysFound) => {
+                info!(
+                    "No matching keys found, retry in {} seconds",
+                    check_duration.as_secs()
+                );
+            }
+            Err(e) => return Err(e),
+        }
+        if fail_counter >= retries {
+            return Err(WorkerError::NoKeysFound);
+        }
+        fail_counter += 1;
+    }
+}
+
+// applies touch policies, cardholder data and PINs to card holding the keys
+fn configure_card(
+    backend: &dyn CardBackend,
+    gpg_home: &str,
+    serial: &str,
+    cardholder: &Cardholder,
+    touch: &TouchPolicies,
+    pins: Option<&CardPins>,
+) -> Result<(), WorkerError> {
+    backend.set_touch(serial, touch, ADMIN_PIN)?;
+    // job status has no place for applied policies
+    info!(
+        "Key ({serial}) touch policies: sign {:?}, encrypt {:?}, auth {:?}",
+        touch.sign, touch.encrypt, touch.auth
+    );
+    backend.set_cardholder(gpg_home, serial, cardholder)?;
+    debug!("Cardholder data set");
+    if let Some(pins) = pins {
+        backend.set_pins(serial, &CardPins::default(), pins)?;
+        debug!("Card PINs randomized");
+    }
+    Ok(())
+}
+
+// checks that card slots hold keys from exported public key
+fn check_card_fingerprints(
+    status: &CardStatus,
+    subkeys: &SubkeyFingerprints,
+    serial: &str,
+) -> Result<(), WorkerError> {
+    let expected = [&subkeys.sign, &subkeys.encrypt, &subkeys.auth];
+    for ((usage, expected), actual) in [SubkeyUsage::Sign, SubkeyUsage::Encrypt, SubkeyUsage::Auth]
+        .into_iter()
+        .zip(expected)
+        .zip(&status.fingerprints)
+    {
+        match actual {
+            Some(actual) if actual.eq_ignore_ascii_case(expected) => {}
+            actual => {
+                return Err(WorkerError::VerificationFailed {
+                    serial: serial.into(),
+                    reason: format!(
+                        "{usage:?} slot holds {}, expected {expected}",
+                        actual.as_deref().unwrap_or("no key")
+                    ),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Confirms that card holds generated keys and each of them can be used.
+/// Slots requiring touch are not exercised, as nobody may be there to touch the key.
+fn verify_card(
+    backend: &dyn CardBackend,
+    gpg_home: &str,
+    serial: &str,
+    subkeys: &SubkeyFingerprints,
+    touch: &TouchPolicies,
+    user_pin: &str,
+) -> Result<(), WorkerError> {
+    check_card_fingerprints(&backend.status(gpg_home, serial)?, subkeys, serial)?;
+    debug!("Card ({serial}) fingerprints match exported key");
+    for (usage, fingerprint, touch) in [
+        (SubkeyUsage::Sign, &subkeys.sign, touch.sign),
+        (SubkeyUsage::Encrypt, &subkeys.encrypt, touch.encrypt),
+        (SubkeyUsage::Auth, &subkeys.auth, touch.auth),
+    ] {
+        if touch != TouchPolicy::Off {
+            info!("{usage:?} key requires touch, skipping test operation");
+            continue;
+        }
+        if !backend.test_key(gpg_home, serial, usage, fingerprint, user_pin)? {
+            return Err(WorkerError::VerificationFailed {
+                serial: serial.into(),
+                reason: format!("{usage:?} test operation failed"),
+            });
+        }
+        debug!("{usage:?} test operation succeeded");
+    }
+    Ok(())
+}
+
+/// Keys claimed by a job and the ones it has wiped. Wiped keys are reset again
+/// when the job fails, so they are left either fully provisioned or clean.
+#[derive(Default)]
+struct JobCards {
+    claims: Vec<CardClaim>,
+    wiped: Vec<String>,
+    /// Auto-selected keys left alone because they already hold keys
+    skipped: HashSet<String>,
+    /// Refusal of the last skipped key
+    refused: Option<WorkerError>,
+}
+
+impl JobCards {
+    fn claim(&mut self, claim: CardClaim) -> String {
+        let serial = claim.serial().to_string();
+        self.claims.push(claim);
+        serial
+    }
+
+    // checks that claimed key may be wiped, auto-selected key protected by reset policy
+    // is released and skipped, so job can wait for the next one
+    fn check_claimed(
+        &mut self,
+        claim: CardClaim,
+        status: &CardStatus,
+        config: &Config,
+        requested: bool,
+        email: &str,
+    ) -> Result<Option<String>, WorkerError> {
+        match check_reset_policy(status, config.reset_policy, claim.serial(), email) {
+            Ok(()) => Ok(Some(self.claim(claim))),
+            Err(e @ WorkerError::KeyInUse { .. }) if !requested => {
+                info!("{e}, skipping it");
+                self.skipped.insert(claim.serial().to_string());
+                self.refused = Some(e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // skipped keys are not connected keys for `wait_for_card`, so running out of keys
+    // after skipping reports why the last one was refused
+    fn wait_failed(&mut self, error: WorkerError) -> WorkerError {
+        match (error, self.refused.take()) {
+            (WorkerError::NoKeysFound, Some(refused)) => refused,
+            (error, _) => error,
+        }
+    }
+
+    fn reset(&mut self, backend: &dyn CardBackend, serial: &str) -> Result<(), WorkerError> {
+        // failed reset can leave the card in any state, so it's tracked beforehand
+        self.wiped.push(serial.into());
+        backend.reset(serial)
+    }
+
+    fn mark_provisioned(&mut self) {
+        for claim in &mut self.claims {
+            claim.mark_provisioned();
+        }
+    }
+
+    // resets wiped keys and describes the outcome, None if no key was changed
+    fn rollback(&self, backend: &dyn CardBackend) -> Option<String> {
+        if self.wiped.is_empty() {
+            return None;
+        }
+        let outcome = self
+            .wiped
+            .iter()
+            .map(|serial| match backend.reset(serial) {
+                Ok(()) => format!("key {serial} reset to factory state"),
+                Err(e) => format!("rollback of key {serial} failed: {e}"),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some(outcome)
+    }
+}
+
+pub async fn provision_key(
+    config: &Config,
+    job: &proto::GetJobResponse,
+    gpg_command: &str,
+    backend: &dyn CardBackend,
+    registry: &SharedRegistry,
+) -> Result<ProvisioningInfo, WorkerError> {
+    let mut cards = JobCards::default();
+    let mut progress = Progress::new(job.job_id);
+    let result = provision(
+        config,
+        job,
+        gpg_command,
+        backend,
+        registry,
+        &mut cards,
+        &mut progress,
+    )
+    .await;
+    match result.map_err(|error| progress.fail(error)) {
+        Ok(info) => {
+            cards.mark_provisioned();
+            info!("Yubikey openpgp provisioning completed.");
+            Ok(info)
+        }
+        Err(error) => match cards.rollback(backend) {
+            Some(rollback) => {
+                warn!("Provisioning failed, {rollback}");
+                Err(WorkerError::ProvisioningFailed {
+                    error: Box::new(error),
+                    rollback,
+                })
+            }
+            None => Err(error),
+        },
+    }
+}
+
+async fn provision(
+    config: &Config,
+    job: &proto::GetJobResponse,
+    gpg_command: &str,
+    backend: &dyn CardBackend,
+    registry: &SharedRegistry,
+    cards: &mut JobCards,
+    progress: &mut Progress,
+) -> Result<ProvisioningInfo, WorkerError> {
+    let full_name = format!("{} {}", job.first_name, job.last_name);
+    debug!("Provisioning start for: {}", &job.email);
+    let profile = KeyProfile::from(config);
+    let touch = TouchPolicies::from(config);
+    let (serial, gpg_session) = loop {
+        progress.enter(Stage::Detect);
+        let claim = wait_for_card(
+            config,
+            backend,
+            registry,
+            config.yubikey_serial.as_deref(),
+            config.smartcard_retries,
+            &cards.skipped,
+        )
+        .await
+        .map_err(|e| cards.wait_failed(e))?;
+        let serial = claim.serial().to_string();
+        debug!("Key with serial ({serial}) found");
+        progress.enter(Stage::Preflight);
+        preflight(
+            &backend.device_info(&serial)?,
+            &profile,
+            config.key_generation,
+            &touch,
+        )?;
+        let gpg_session = GpgSession::new(config, &serial)?;
+        debug!("Temporary GPG session crated");
+        backend.attach(gpg_session.home(), &serial)?;
+        let status = backend.status(gpg_session.home(), &serial)?;
+        let requested = config.yubikey_serial.is_some();
+        if let Some(serial) = cards.check_claimed(claim, &status, config, requested, &job.email)? {
+            break (serial, gpg_session);
+        }
+    };
+    let gpg_home = gpg_session.home().to_string();
+    progress.enter(Stage::Reset);
+    debug!("Resetting card to factory");
+    cards.reset(backend, &serial)?;
+    debug!("OpenPGP Key app restored to factory.");
+    progress.enter(Stage::Generate);
+    let fingerprint = match config.key_generation {
+        KeyGenerationMode::Host => gen_key(
+            gpg_command,
+            &config.gpg_debug_level,
+            &gpg_home,
+            &full_name,
+            &job.email,
+            &profile,
+        )?,
+        KeyGenerationMode::Card => {
+            backend.generate_keys(&gpg_home, &serial, &full_name, &job.email, &profile)?
+        }
+    };
+    debug!("OpenPGP key {fingerprint} for {} created", &job.email);
+    progress.enter(Stage::Export);
+    let pgp = export_public(gpg_command, &gpg_home, &job.email)?;
+    let ssh = export_ssh(gpg_command, &gpg_home, &job.email)?;
+    let key = find_key(gpg_command, &gpg_home, &job.email)?;
+    let subkeys = subkey_fingerprints(&key)?;
+    debug!("Subkey fingerprints: {subkeys:?}");
+    let revocation = revocation_certificate(&gpg_home, &fingerprint)?;
+    debug!("Revocation certificate for {fingerprint} read");
+    let escrow_enabled = !config.escrow_recipient_keys.is_empty();
+    if escrow_enabled {
+        save_escrow(
+            config,
+            gpg_command,
+            &gpg_home,
+            &format!("{serial}-{fingerprint}-revocation.asc"),
+            &revocation,
+        )?;
+    } else if let Some(revocation_dir) = &config.revocation_dir {
+        let path = revocation_dir.join(format!("{serial}-{fingerprint}-revocation.asc"));
+        write_private(&path, &revocation)?;
+        info!("Revocation certificate saved to {}", path.display());
+    }
+    // secret subkeys are replaced with card stubs by keytocard, export them before
+    if escrow_enabled && config.key_generation == KeyGenerationMode::Host {
+        let secret = export_secret_subkeys(gpg_command, &gpg_home, &job.email)?;
+        save_escrow(
+            config,
+            gpg_command,
+            &gpg_home,
+            &format!("{serial}-{fingerprint}-escrow.asc"),
+            &secret,
+        )?;
+    }
+    if config.backup_card {
+        backup_private_keys(&gpg_home)?;
+    }
+    if config.key_generation == KeyGenerationMode::Host {
+        progress.enter(Stage::KeyToCard);
+        backend.load_keys(&gpg_home, &serial, &job.email, &subkeys)?;
+        debug!("Subkeys saved in yubikey");
+    }
+    progress.enter(Stage::Configure);
+    let cardholder = Cardholder::new(config, job, &fingerprint);
+    let pins = config.randomize_pins.then(|| CardPins::random(config));
+    configure_card(
+        backend,
+        &gpg_home,
+        &serial,
+        &cardholder,
+        &touch,
+        pins.as_ref(),
+    )?;
+    progress.enter(Stage::Verify);
+    let user_pin = pins.as_ref().map_or(USER_PIN, |pins| pins.user.as_str());
+    verify_card(backend, &gpg_home, &serial, &subkeys, &touch, user_pin)?;
+    debug!("Key ({serial}) verified");
+    // claimed key is never selected again, so backup key is always a different one
+    let backup_serial = if config.backup_card {
+        info!("Insert backup key");
+        let backup_serial = loop {
+            progress.enter(Stage::Detect);
+            let claim = wait_for_card(
+                config,
+                backend,
+                registry,
+                None,
+                config.backup_card_retries,
+                &cards.skipped,
+            )
+            .await
+            .map_err(|e| cards.wait_failed(e))?;
+            let backup_serial = claim.serial().to_string();
+            debug!("Backup key with serial ({backup_serial}) found");
+            progress.enter(Stage::Preflight);
+            preflight(
+                &backend.device_info(&backup_serial)?,
+                &profile,
+                config.key_generation,
+                &touch,
+            )?;
+            backend.attach(&gpg_home, &backup_serial)?;
+            let status = backend.status(&gpg_home, &backup_serial)?;
+            if let Some(serial) = cards.check_claimed(claim, &status, config, false, &job.email)? {
+                break serial;
+            }
+        };
+        progress.enter(Stage::Reset);
+        cards.reset(backend, &backup_serial)?;
+        debug!("Backup key OpenPGP app restored to factory.");
+        progress.enter(Stage::KeyToCard);
+        restore_private_keys(&gpg_home)?;
+        backend.load_keys(&gpg_home, &backup_serial, &job.email, &subkeys)?;
+        debug!("Subkeys saved in backup yubikey");
+        progress.enter(Stage::Configure);
+        configure_card(
+            backend,
+            &gpg_home,
+            &backup_serial,
+            &cardholder,
+            &touch,
+            pins.as_ref(),
+        )?;
+        progress.enter(Stage::Verify);
+        verify_card(
+            backend,
+            &gpg_home,
+            &backup_serial,
+            &subkeys,
+            &touch,
+            user_pin,
+        )?;
+        debug!("Backup key ({backup_serial}) verified");
+        Some(backup_serial)
+    } else {
+        None
+    };
+    progress.enter(Stage::Cleanup);
+    if let Some(pins) = pins {
+        let serials = match &backup_serial {
+            Some(backup_serial) => format!("{serial}, {backup_serial}"),
+            None => serial.clone(),
+        };
+        let mut envelope = pins.envelope(&serials);
+        let mut file_name = format!("{serial}-pins.txt");
+        if let Some(recipient) = &config.pin_recipient_key {
+            envelope = encrypt_to_recipients(
+                gpg_command,
+                &gpg_home,
+                slice::from_ref(recipient),
+                &envelope,
+            )?;
+            file_name = format!("{serial}-pins.asc");
+            debug!("PINs encrypted to {}", recipient.display());
+        }
+        if let Some(output_dir) = &config.pin_output_dir {
+            let path = output_dir.join(file_name);
+            write_private(&path, &envelope)?;
+            info!("Card PINs saved to {}", path.display());
+        }
+    }
+    gpg_session.close()?;
+    log_key_metadata(&key);
+    Ok(ProvisioningInfo {
+        pgp,
+        ssh,
+        serial,
+        backup_serial,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(target_family = "unix")]
+    use std::os::unix::fs::PermissionsExt;
+    use std::{
+        env,
+        path::{Path, PathBuf},
+    };
+
+    use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+    use which::which;
+
+    use super::*;
+    use crate::config::KeyAlgorithm;
+    use crate::simulated_card::{SimulatedCard, SimulatedKey};
+
+    const SERIAL: &str = "12345678";
+
+    fn profile(algorithm: KeyAlgorithm) -> KeyProfile {
+        KeyProfile {
+            algorithm,
+            expiry: "2y".into(),
+        }
+    }
+
+    fn device(firmware: Option<FirmwareVersion>) -> YubiKeyDevice {
+        let mut device = SimulatedKey::new(SERIAL).device;
+        device.firmware = firmware;
+        device
+    }
+
+    #[test]
+    fn test_preflight() {
+        use KeyAlgorithm::*;
+        use KeyGenerationMode::*;
+        let off = TouchPolicies {
+            sign: TouchPolicy::Off,
+            encrypt: TouchPolicy::Off,
+            auth: TouchPolicy::Off,
+        };
+        let touch = |policy| TouchPolicies {
+            sign: policy,
+            ..off
+        };
+        let version = |major, minor, patch| Some(FirmwareVersion::new(major, minor, patch));
+        for (firmware, algorithm, mode, touch, passes) in [
+            (version(5, 2, 3), Ed25519, Host, off, true),
+            (version(5, 2, 2), Ed25519, Host, off, false),
+            (version(4, 0, 0), Rsa4096, Host, off, true),
+            (version(3, 5, 0), Rsa4096, Host, off, false),
+            (version(3, 5, 0), Rsa2048, Host, off, true),
+            // ROCA affected range, only on-card RSA generation is refused
+            (version(4, 2, 5), Rsa2048, Card, off, true),
+            (version(4, 2, 6), Rsa2048, Card, off, false),
+            (version(4, 3, 4), Rsa4096, Card, off, false),
+            (version(4, 3, 5), Rsa4096, Card, off, true),
+            (version(4, 3, 4), Rsa4096, Host, off, true),
+            (
+                version(4, 1, 9),
+                Rsa2048,
+                Host,
+                touch(TouchPolicy::On),
+                false,
+            ),
+            (
+                version(4, 2, 0),
+                Rsa2048,
+                Host,
+                touch(TouchPolicy::Fixed),
+                true,
+            ),
+            (
+                version(5, 2, 0),
+                Rsa2048,
+                Host,
+                touch(TouchPolicy::Cached),
+                false,
+            ),
+            (
+                version(5, 2, 1),
+                Rsa2048,
+                Host,
+                touch(TouchPolicy::CachedFixed),
+                true,
+            ),
+            // unknown firmware skips version checks
+            (None, Ed25519, Card, touch(TouchPolicy::Cached), true),
+        ] {
+            let result = preflight(&device(firmware), &profile(algorithm), mode, &touch);
+            assert_eq!(
+                result.is_ok(),
+                passes,
+                "{firmware:?} {algorithm:?} {mode:?} {touch:?}: {result:?}"
+            );
+            if !passes {
+                assert!(matches!(
+                    result,
+                    Err(WorkerError::UnsupportedFirmware { .. })
+                ));
+            }
+        }
+        let mut device = device(version(5, 4, 3));
+        device.usb_applications = vec!["OTP".into(), "FIDO2".into()];
+        assert!(matches!(
+            preflight(&device, &profile(Ed25519), Host, &off),
+            Err(WorkerError::OpenPgpDisabled(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_reset_policy() {
+        let email = "jan.kowalski@example.com";
+        let empty = CardStatus::default();
+        let mut own = CardStatus {
+            login: Some(email.into()),
+            ..Default::default()
+        };
+        own.fingerprints[0] = Some("AB".repeat(20));
+        let other = CardStatus {
+            login: Some("someone@example.com".into()),
+            ..own.clone()
+        };
+        for (status, policy, allowed) in [
+            (&empty, ResetPolicy::Refuse, true),
+            (&empty, ResetPolicy::SameUser, true),
+            (&empty, ResetPolicy::Allow, true),
+            (&own, ResetPolicy::Refuse, false),
+            (&own, ResetPolicy::SameUser, true),
+            (&own, ResetPolicy::Allow, true),
+            (&other, ResetPolicy::Refuse, false),
+            (&other, ResetPolicy::SameUser, false),
+            (&other, ResetPolicy::Allow, true),
+        ] {
+            let result = check_reset_policy(status, policy, SERIAL, email);
+            assert_eq!(result.is_ok(), allowed, "{status:?} {policy:?}");
+            if !allowed {
+                assert!(matches!(result, Err(WorkerError::KeyInUse { .. })));
+            }
+        }
+    }
+
+    // provisioning runs real gpg on the host
+    fn gpg() -> &'static str {
+        ["gpg", "gpg2"]
+            .into_iter()
+            .find(|command| which(command).is_ok())
+            .expect("gpg or gpg2 has to be installed to run provisioning tests")
+    }
+
+    // escrow directory with recipient key generated for the test, removed when dropped
+    struct TestEscrow {
+        dir: PathBuf,
+        recipient: PathBuf,
+    }
+
+    impl TestEscrow {
+        fn new(gpg_command: &str) -> Self {
+            let suffix: String = OsRng
+                .sample_iter(Alphanumeric)
+                .take(12)
+                .map(char::from)
+                .collect();
+            let dir = env::temp_dir().join(format!("yubikey-provision-escrow-{suffix}"));
+            fs::create_dir(&dir).unwrap();
+            let session = GpgSession::new(&Config::default(), "escrow").unwrap();
+            let email = "escrow@example.com";
+            let profile = profile(KeyAlgorithm::Ed25519);
+            gen_key(
+                gpg_command,
+                "none",
+                session.home(),
+                "Escrow",
+                email,
+                &profile,
+            )
+            .unwrap();
+            let recipient = dir.join("recipient.asc");
+            fs::write(
+                &recipient,
+                export_public(gpg_command, session.home(), email).unwrap(),
+            )
+            .unwrap();
+            Self { dir, recipient }
+        }
+    }
+
+    impl Drop for TestEscrow {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn config(escrow: &TestEscrow) -> Config {
+        let mut config = Config::default();
+        config.key_algorithm = KeyAlgorithm::Ed25519;
+        config.smartcard_retries = 0;
+        config.escrow_recipient_keys = vec![escrow.recipient.clone()];
+        config.escrow_dir = Some(escrow.dir.clone());
+        config
+    }
+
+    fn job() -> proto::GetJobResponse {
+        proto::GetJobResponse {
+            first_name: "Jan".into(),
+            last_name: "Kowalski".into(),
+            email: "jan.kowalski@example.com".into(),
+            job_id: 1,
+        }
+    }
+
+    // escrow or revocation file of the test key, named after fingerprint of generated key
+    fn saved_file(dir: &Path, suffix: &str) -> Option<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(SERIAL) && name.ends_with(suffix))
+            })
+    }
+
+    // each slot holds a different key
+    fn assert_card_keys(card: &SimulatedKey) {
+        let [sign, encrypt, auth] = &card.status.fingerprints;
+        let (Some(sign), Some(encrypt), Some(auth)) = (sign, encrypt, auth) else {
+            panic!("card slots not filled: {:?}", card.status.fingerprints);
+        };
+        assert!(sign != encrypt && encrypt != auth && sign != auth);
+    }
+
+    #[tokio::test]
+    async fn test_provision_simulated_card() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let backend = SimulatedCard::new(gpg_command);
+        backend.insert(SimulatedKey::new(SERIAL));
+        let registry = SharedRegistry::default();
+        let info = provision_key(&config(&escrow), &job(), gpg_command, &backend, &registry)
+            .await
+            .unwrap();
+        assert_eq!(info.serial, SERIAL);
+        for suffix in ["-revocation.asc", "-escrow.asc"] {
+            let path = saved_file(&escrow.dir, suffix).unwrap();
+            assert!(fs::read_to_string(path)
+                .unwrap()
+                .starts_with("-----BEGIN PGP MESSAGE-----"));
+        }
+        let card = backend.card(SERIAL).unwrap();
+        assert_card_keys(&card);
+        assert_eq!(card.status.cardholder(), "Jan Kowalski");
+        assert_eq!(
+            card.status.login.as_deref(),
+            Some("jan.kowalski@example.com")
+        );
+        let registry = registry.lock().unwrap();
+        assert!(registry.provisioned.contains(SERIAL));
+        assert!(registry.in_use.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_provision_on_card() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let backend = SimulatedCard::new(gpg_command);
+        backend.insert(SimulatedKey::new(SERIAL));
+        let mut config = config(&escrow);
+        config.key_generation = KeyGenerationMode::Card;
+        provision_key(
+            &config,
+            &job(),
+            gpg_command,
+            &backend,
+            &SharedRegistry::default(),
+        )
+        .await
+        .unwrap();
+        // secret keys exist only on the card, there is nothing to escrow
+        assert!(saved_file(&escrow.dir, "-escrow.asc").is_none());
+        assert!(saved_file(&escrow.dir, "-revocation.asc").is_some());
+        assert_card_keys(&backend.card(SERIAL).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_provision_revocation_without_escrow() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let backend = SimulatedCard::new(gpg_command);
+        backend.insert(SimulatedKey::new(SERIAL));
+        let mut config = config(&escrow);
+        config.escrow_recipient_keys.clear();
+        config.escrow_dir = None;
+        config.revocation_dir = Some(escrow.dir.clone());
+        provision_key(
+            &config,
+            &job(),
+            gpg_command,
+            &backend,
+            &SharedRegistry::default(),
+        )
+        .await
+        .unwrap();
+        assert!(saved_file(&escrow.dir, "-escrow.asc").is_none());
+        let revocation = saved_file(&escrow.dir, "-revocation.asc").unwrap();
+        assert!(fs::read_to_string(&revocation)
+            .unwrap()
+            .starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"));
+        assert_eq!(
+            fs::metadata(&revocation).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+
+    #[tokio::test]
+    async fn test_provision_backup_card() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let backend = SimulatedCard::new(gpg_command);
+        backend.insert(SimulatedKey::new(SERIAL));
+        backend.insert(SimulatedKey::new("23456789"));
+        let mut config = config(&escrow);
+        config.backup_card = true;
+        config.backup_card_retries = 0;
+        let registry = SharedRegistry::default();
+        let info = provision_key(&config, &job(), gpg_command, &backend, &registry)
+            .await
+            .unwrap();
+        assert_eq!(info.serial, SERIAL);
+        assert_eq!(info.backup_serial.as_deref(), Some("23456789"));
+        let primary = backend.card(SERIAL).unwrap();
+        let backup = backend.card("23456789").unwrap();
+        assert_card_keys(&primary);
+        assert_eq!(primary.status.fingerprints, backup.status.fingerprints);
+        assert_eq!(backup.status.login, primary.status.login);
+        let registry = registry.lock().unwrap();
+        assert!(registry.provisioned.contains(SERIAL));
+        assert!(registry.provisioned.contains("23456789"));
+    }
+
+    #[tokio::test]
+    async fn test_provision_random_pins_and_touch() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let backend = SimulatedCard::new(gpg_command);
+        backend.insert(SimulatedKey::new(SERIAL));
+        let mut config = config(&escrow);
+        config.randomize_pins = true;
+        config.pin_output_dir = Some(escrow.dir.clone());
+        config.touch_sign = TouchPolicy::On;
+        config.touch_auth = TouchPolicy::Cached;
+        provision_key(
+            &config,
+            &job(),
+            gpg_command,
+            &backend,
+            &SharedRegistry::default(),
+        )
+        .await
+        .unwrap();
+        let card = backend.card(SERIAL).unwrap();
+        assert_ne!(card.pins.user, USER_PIN);
+        assert_ne!(card.pins.admin, ADMIN_PIN);
+        assert_eq!(card.pins.user.len(), config.user_pin_length);
+        assert!(card.pins.reset_code.is_some());
+        let pin_file = escrow.dir.join(format!("{SERIAL}-pins.txt"));
+        let envelope = fs::read_to_string(&pin_file).unwrap();
+        assert!(envelope.contains(&card.pins.user));
+        assert_eq!(
+            fs::metadata(&pin_file).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let touch = card.touch.unwrap();
+        assert_eq!(
+            [touch.sign, touch.encrypt, touch.auth],
+            [TouchPolicy::On, TouchPolicy::Off, TouchPolicy::Cached]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_provision_refuses_used_card() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let backend = SimulatedCard::new(gpg_command);
+        let mut key = SimulatedKey::new(SERIAL);
+        key.status.login = Some("someone@example.com".into());
+        key.status.fingerprints[0] = Some("AB".repeat(20));
+        backend.insert(key.clone());
+        let mut config = config(&escrow);
+        config.yubikey_serial = Some(SERIAL.into());
+        let result = provision_key(
+            &config,
+            &job(),
+            gpg_command,
+            &backend,
+            &SharedRegistry::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(WorkerError::StageFailed { stage: Stage::Preflight, ref error })
+                if matches!(**error, WorkerError::KeyInUse { .. })
+        ));
+        assert_eq!(backend.card(SERIAL).unwrap().status, key.status);
+    }
+
+    #[tokio::test]
+    async fn test_provision_reports_skipped_card() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let backend = SimulatedCard::new(gpg_command);
+        let mut key = SimulatedKey::new(SERIAL);
+        key.status.fingerprints[0] = Some("AB".repeat(20));
+        backend.insert(key.clone());
+        let result = provision_key(
+            &config(&escrow),
+            &job(),
+            gpg_command,
+            &backend,
+            &SharedRegistry::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(WorkerError::StageFailed { stage: Stage::Detect, ref error })
+                if matches!(**error, WorkerError::KeyInUse { ref serial, .. } if serial == SERIAL)
+        ));
+        assert_eq!(backend.card(SERIAL).unwrap().status, key.status);
+    }
+
+    #[tokio::test]
+    async fn test_provision_skips_used_card() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let backend = SimulatedCard::new(gpg_command);
+        let mut key = SimulatedKey::new(SERIAL);
+        key.status.fingerprints[0] = Some("AB".repeat(20));
+        backend.insert(key.clone());
+        backend.insert(SimulatedKey::new("23456789"));
+        let info = provision_key(
+            &config(&escrow),
+            &job(),
+            gpg_command,
+            &backend,
+            &SharedRegistry::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(info.serial, "23456789");
+        assert_eq!(backend.card(SERIAL).unwrap().status, key.status);
+    }
+
+    #[tokio::test]
+    async fn test_provision_rolls_back_failed_card() {
+        let gpg_command = gpg();
+        let escrow = TestEscrow::new(gpg_command);
+        let mut backend = SimulatedCard::new(gpg_command);
+        backend.fail_load_keys = true;
+        backend.insert(SimulatedKey::new(SERIAL));
+        let registry = SharedRegistry::default();
+        let result =
+            provision_key(&config(&escrow), &job(), gpg_command, &backend, &registry).await;
+        assert!(matches!(
+            result,
+            Err(WorkerError::ProvisioningFailed { .. })
+        ));
+        assert!(!backend.card(SERIAL).unwrap().status.has_keys());
+        assert!(!registry.lock().unwrap().provisioned.contains(SERIAL));
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use crate::error::WorkerError;
+
+/// Picks key to provision from connected ones, keys in `busy` are never selected.
+/// Requested serial is used if present, otherwise the first key not in `excluded`,
+/// i.e. neither provisioned by this worker nor skipped by the job.
+pub fn select_card(
+    serials: &[String],
+    requested: Option<&str>,
+    busy: &HashSet<String>,
+    excluded: &HashSet<String>,
+) -> Result<String, WorkerError> {
+    let mut candidates = serials.iter().filter(|serial| !busy.contains(*serial));
+    let selected = match requested {
+        Some(requested) => candidates.find(|serial| *serial == requested),
+        None => candidates.find(|serial| !excluded.contains(*serial)),
+    };
+    selected.cloned().ok_or(WorkerError::NoKeysFound)
+}
+
+/// Keys used by running jobs and provisioned by this worker, shared between parallel jobs.
+#[derive(Debug, Default)]
+pub struct CardRegistry {
+    pub in_use: HashSet<String>,
+    pub provisioned: HashSet<String>,
+}
+
+pub type SharedRegistry = Arc<Mutex<CardRegistry>>;
+
+/// Key reserved for a job, released when dropped.
+#[derive(Debug)]
+pub struct CardClaim {
+    registry: SharedRegistry,
+    serial: String,
+    provisioned: bool,
+}
+
+impl CardClaim {
+    /// Selects key from connected ones and reserves it for calling job.
+    pub fn claim(
+        registry: &SharedRegistry,
+        serials: &[String],
+        requested: Option<&str>,
+        skipped: &HashSet<String>,
+    ) -> Result<Self, WorkerError> {
+        let mut guard = registry.lock().map_err(|_| WorkerError::Registry)?;
+        let excluded = guard.provisioned.union(skipped).cloned().collect();
+        let serial = select_card(serials, requested, &guard.in_use, &excluded)?;
+        guard.in_use.insert(serial.clone());
+        Ok(Self {
+            registry: Arc::clone(registry),
+            serial,
+            provisioned: false,
+        })
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    pub fn mark_provisioned(&mut self) {
+        self.provisioned = true;
+    }
+}
+
+impl Drop for CardClaim {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = self.registry.lock() {
+            guard.in_use.remove(&self.serial);
+            if self.provisioned {
+                guard.provisioned.insert(self.serial.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_card() {
+        let serials =
+            |list: &[&str]| -> Vec<String> { list.iter().map(|s| s.to_string()).collect() };
+        let set =
+            |list: &[&str]| -> HashSet<String> { list.iter().map(|s| s.to_string()).collect() };
+        for (connected, requested, busy, excluded, expected) in [
+            (&["1"][..], None, &[][..], &[][..], Some("1")),
+            (&["1"], None, &[], &["1"], None),
+            (&["1", "2"], None, &[], &["1"], Some("2")),
+            (&["1", "2"], None, &["1"], &[], Some("2")),
+            (&["1", "2"], Some("1"), &[], &["1"], Some("1")),
+            (&["1", "2"], Some("1"), &["1"], &[], None),
+            (&["1"], Some("3"), &[], &[], None),
+            (&[], None, &[], &[], None),
+        ] {
+            let selected = select_card(&serials(connected), requested, &set(busy), &set(excluded));
+            assert_eq!(
+                selected.ok().as_deref(),
+                expected,
+                "{connected:?} {requested:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{
+    card::CardBackend,
+    cardholder::Cardholder,
+    error::WorkerError,
+    gpg::{
+        find_key, gen_key, subkey_fingerprints, KeyProfile, SubkeyFingerprints, SubkeyUsage,
+        TouchPolicies,
+    },
+    gpg_output::CardStatus,
+    pin::CardPins,
+    ykman::{FirmwareVersion, YubiKeyDevice},
+};
+
+/// State of a simulated key.
+#[derive(Debug, Clone)]
+pub struct SimulatedKey {
+    pub device: YubiKeyDevice,
+    pub status: CardStatus,
+    pub pins: CardPins,
+    pub touch: Option<TouchPolicies>,
+}
+
+impl SimulatedKey {
+    pub fn new(serial: &str) -> Self {
+        Self {
+            device: YubiKeyDevice {
+                serial: serial.into(),
+                device_type: "YubiKey 5 NFC".into(),
+                firmware: Some(FirmwareVersion {
+                    major: 5,
+                    minor: 4,
+                    patch: 3,
+                }),
+                form_factor: "Keychain (USB-A)".into(),
+                usb_applications: vec!["OTP".into(), "FIDO2".into(), "OpenPGP".into()],
+                nfc_applications: Vec::new(),
+                fips: false,
+            },
+            status: CardStatus {
+                serial: Some(serial.into()),
+                ..Default::default()
+            },
+            pins: CardPins::default(),
+            touch: None,
+        }
+    }
+}
+
+/// In-memory keys for running provisioning without hardware. Keys are generated
+/// by gpg on the host, card slots only record fingerprints of loaded subkeys.
+pub struct SimulatedCard {
+    gpg_command: String,
+    keys: Mutex<HashMap<String, SimulatedKey>>,
+    /// Makes `load_keys` fail after filling the signature slot
+    pub fail_load_keys: bool,
+}
+
+impl SimulatedCard {
+    pub fn new(gpg_command: &str) -> Self {
+        Self {
+            gpg_command: gpg_command.into(),
+            keys: Mutex::default(),
+            fail_load_keys: false,
+        }
+    }
+
+    pub fn insert(&self, key: SimulatedKey) {
+        self.lock().insert(key.device.serial.clone(), key);
+    }
+
+    pub fn card(&self, serial: &str) -> Option<SimulatedKey> {
+        self.lock().get(serial).cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, SimulatedKey>> {
+        self.keys.lock().expect("simulated keys lock poisoned")
+    }
+
+    // applies change to key with given serial
+    fn update<T>(
+        &self,
+        serial: &str,
+        change: impl FnOnce(&mut SimulatedKey) -> Result<T, WorkerError>,
+    ) -> Result<T, WorkerError> {
+        match self.lock().get_mut(serial) {
+            Some(key) => change(key),
+            None => Err(WorkerError::SerialNotFound),
+        }
+    }
+}
+
+impl CardBackend for SimulatedCard {
+    fn list_serials(&self) -> Result<Vec<String>, WorkerError> {
+        let mut serials: Vec<String> = self.lock().keys().cloned().collect();
+        serials.sort();
+        Ok(serials)
+    }
+
+    fn device_info(&self, serial: &str) -> Result<YubiKeyDevice, WorkerError> {
+        self.update(serial, |key| Ok(key.device.clone()))
+    }
+
+    fn attach(&self, _gpg_home: &str, serial: &str) -> Result<(), WorkerError> {
+        self.update(serial, |_| Ok(()))
+    }
+
+    fn status(&self, _gpg_home: &str, serial: &str) -> Result<CardStatus, WorkerError> {
+        self.update(serial, |key| Ok(key.status.clone()))
+    }
+
+    fn reset(&self, serial: &str) -> Result<(), WorkerError> {
+        self.update(serial, |key| {
+            *key = SimulatedKey::new(serial);
+            Ok(())
+        })
+    }
+
+    fn load_keys(
+        &self,
+        _gpg_home: &str,
+        serial: &str,
+        _email: &str,
+        subkeys: &SubkeyFingerprints,
+    ) -> Result<(), WorkerError> {
+        self.update(serial, |key| {
+            key.status.fingerprints[0] = Some(subkeys.sign.clone());
+            if self.fail_load_keys {
+                return Err(WorkerError::Gpg("simulated keytocard failure".into()));
+            }
+            key.status.fingerprints[1] = Some(subkeys.encrypt.clone());
+            key.status.fingerprints[2] = Some(subkeys.auth.clone());
+            Ok(())
+        })
+    }
+
+    fn generate_keys(
+        &self,
+        gpg_home: &str,
+        serial: &str,
+        full_name: &str,
+        email: &str,
+        profile: &KeyProfile,
+    ) -> Result<String, WorkerError> {
+        let fingerprint = gen_key(
+            &self.gpg_command,
+            "none",
+            gpg_home,
+            full_name,
+            email,
+            profile,
+        )?;
+        let subkeys = subkey_fingerprints(&find_key(&self.gpg_command, gpg_home, email)?)?;
+        self.load_keys(gpg_home, serial, email, &subkeys)?;
+        Ok(fingerprint)
+    }
+
+    fn set_cardholder(
+        &self,
+        _gpg_home: &str,
+        serial: &str,
+        cardholder: &Cardholder,
+    ) -> Result<(), WorkerError> {
+        self.update(serial, |key| {
+            // empty name is not written
+            if !cardholder.surname.is_empty() {
+                key.status.given_name = Some(cardholder.given_name.clone());
+                key.status.surname = Some(cardholder.surname.clone());
+            }
+            key.status.login = Some(cardholder.login.clone());
+            Ok(())
+        })
+    }
+
+    fn set_pins(
+        &self,
+        serial: &str,
+        current: &CardPins,
+        new: &CardPins,
+    ) -> Result<(), WorkerError> {
+        self.update(serial, |key| {
+            if key.pins.user != current.user || key.pins.admin != current.admin {
+                return Err(WorkerError::YubikeyManager("wrong PIN".into()));
+            }
+            key.pins = new.clone();
+            Ok(())
+        })
+    }
+
+    fn set_touch(
+        &self,
+        serial: &str,
+        policies: &TouchPolicies,
+        admin_pin: &str,
+    ) -> Result<(), WorkerError> {
+        self.update(serial, |key| {
+            if key.pins.admin != admin_pin {
+                return Err(WorkerError::YubikeyManager("wrong admin PIN".into()));
+            }
+            key.touch = Some(*policies);
+            Ok(())
+        })
+    }
+
+    fn test_key(
+        &self,
+        _gpg_home: &str,
+        serial: &str,
+        usage: SubkeyUsage,
+        fingerprint: &str,
+        user_pin: &str,
+    ) -> Result<bool, WorkerError> {
+        let slot = match usage {
+            SubkeyUsage::Sign => 0,
+            SubkeyUsage::Encrypt => 1,
+            SubkeyUsage::Auth => 2,
+        };
+        self.update(serial, |key| {
+            Ok(key.pins.user == user_pin
+                && key.status.fingerprints[slot].as_deref() == Some(fingerprint))
+        })
+    }
+}
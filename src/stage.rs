@@ -0,0 +1,75 @@
+use std::fmt;
+
+use log::info;
+
+use crate::error::WorkerError;
+
+/// Named stage of provisioning job, backup key goes through card stages again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Waiting for a key and reading its device info
+    Detect,
+    /// Capability and reset policy checks, nothing on the card is changed yet
+    Preflight,
+    Reset,
+    Generate,
+    /// Public key, SSH key, revocation certificate and escrow export
+    Export,
+    KeyToCard,
+    /// Touch policies, cardholder data and PINs
+    Configure,
+    Verify,
+    /// PIN envelope hand-over and gpg session removal
+    Cleanup,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Detect => "detect",
+            Self::Preflight => "preflight",
+            Self::Reset => "reset",
+            Self::Generate => "generate",
+            Self::Export => "export",
+            Self::KeyToCard => "keytocard",
+            Self::Configure => "configure",
+            Self::Verify => "verify",
+            Self::Cleanup => "cleanup",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Current stage of a job. Worker proto has no progress call, so transitions
+/// are logged and the failed stage is named in error sent with job status.
+#[derive(Debug)]
+pub struct Progress {
+    job_id: u32,
+    stage: Option<Stage>,
+}
+
+impl Progress {
+    pub fn new(job_id: u32) -> Self {
+        Self {
+            job_id,
+            stage: None,
+        }
+    }
+
+    pub fn enter(&mut self, stage: Stage) {
+        // TODO: report transition to the server once worker proto has a progress call
+        info!("Job {}: {stage} stage", self.job_id);
+        self.stage = Some(stage);
+    }
+
+    /// Attaches stage in which the job failed to the error.
+    pub fn fail(&self, error: WorkerError) -> WorkerError {
+        match self.stage {
+            Some(stage) => WorkerError::StageFailed {
+                stage,
+                error: Box::new(error),
+            },
+            None => error,
+        }
+    }
+}
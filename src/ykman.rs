@@ -0,0 +1,296 @@
+use std::{fmt, str::FromStr};
+
+use log::debug;
+
+use crate::{
+    error::WorkerError,
+    process::{failure_details, run, Step},
+};
+
+/// YubiKey firmware version, e.g. 5.4.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl FirmwareVersion {
+    pub const fn new(major: u8, minor: u8, patch: u8) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = WorkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split('.').map(str::parse::<u8>);
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => {
+                Ok(Self::new(major, minor, patch))
+            }
+            _ => Err(WorkerError::YubikeyManager(format!(
+                "invalid firmware version: {s}"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Device information reported by `ykman --device <serial> info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YubiKeyDevice {
+    pub serial: String,
+    pub device_type: String,
+    /// Not reported by ykman on some older keys
+    pub firmware: Option<FirmwareVersion>,
+    pub form_factor: String,
+    pub usb_applications: Vec<String>,
+    pub nfc_applications: Vec<String>,
+    pub fips: bool,
+}
+
+// returns serial numbers from `ykman list --serials` output
+pub fn parse_serials(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+// application table columns are separated with tabs and padded with spaces
+fn split_columns(line: &str) -> Vec<&str> {
+    line.split('\t')
+        .map(str::trim)
+        .filter(|column| !column.is_empty())
+        .collect()
+}
+
+/// Parses `ykman info` output. Application table may have USB and NFC columns
+/// or a single USB status column for keys without NFC.
+pub fn parse_info(output: &str) -> Result<YubiKeyDevice, WorkerError> {
+    let mut serial = None;
+    let mut device_type = String::new();
+    let mut firmware = None;
+    let mut form_factor = String::new();
+    let mut usb_applications = Vec::new();
+    let mut nfc_applications = Vec::new();
+    let mut fips = false;
+    // column indexes of the application table, set while inside it
+    let mut table: Option<(Option<usize>, Option<usize>)> = None;
+
+    for line in output.lines() {
+        if let Some((usb_column, nfc_column)) = table {
+            let columns = split_columns(line);
+            // table ends with empty line
+            let Some((name, statuses)) = columns.split_first() else {
+                table = None;
+                continue;
+            };
+            let enabled = |column: Option<usize>| {
+                column.and_then(|index| statuses.get(index)) == Some(&"Enabled")
+            };
+            if enabled(usb_column) {
+                usb_applications.push((*name).to_string());
+            }
+            if enabled(nfc_column) {
+                nfc_applications.push((*name).to_string());
+            }
+            continue;
+        }
+        if line.starts_with("Applications") {
+            let header = split_columns(line);
+            let position = |name| header.iter().skip(1).position(|column| *column == name);
+            // single unnamed column holds USB status
+            let usb_column = if header.len() == 1 {
+                Some(0)
+            } else {
+                position("USB")
+            };
+            table = Some((usb_column, position("NFC")));
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "Serial number" => serial = Some(value.to_string()),
+            "Device type" => {
+                fips |= value.contains("FIPS");
+                device_type = value.to_string();
+            }
+            "Firmware version" => firmware = value.parse().ok(),
+            "Form factor" => form_factor = value.to_string(),
+            key if key.starts_with("FIPS") => fips = true,
+            _ => {}
+        }
+    }
+
+    Ok(YubiKeyDevice {
+        serial: serial.ok_or(WorkerError::SerialNotFound)?,
+        device_type,
+        firmware,
+        form_factor,
+        usb_applications,
+        nfc_applications,
+        fips,
+    })
+}
+
+// returns serial numbers of all connected yubikeys
+pub fn list_serials() -> Result<Vec<String>, WorkerError> {
+    let out = run("ykman", &["list", "--serials"], None, Step::Ykman)?;
+    if !out.status.success() {
+        return Err(WorkerError::YubikeyManager(failure_details(&out)));
+    }
+    Ok(parse_serials(&String::from_utf8(out.stdout)?))
+}
+
+pub fn device_info(serial: &str) -> Result<YubiKeyDevice, WorkerError> {
+    let out = run("ykman", &["--device", serial, "info"], None, Step::Ykman)?;
+    if !out.status.success() {
+        return Err(WorkerError::YubikeyManager(failure_details(&out)));
+    }
+    let device = parse_info(&String::from_utf8(out.stdout)?)?;
+    debug!("Key ({serial}) info: {device:?}");
+    Ok(device)
+}
+
+// returns PC/SC reader name of key with given serial
+pub fn reader_name(serial: &str) -> Result<String, WorkerError> {
+    let out = run("ykman", &["list", "--readers"], None, Step::Ykman)?;
+    if !out.status.success() {
+        return Err(WorkerError::YubikeyManager(failure_details(&out)));
+    }
+    let readers = String::from_utf8(out.stdout)?;
+    for reader in readers
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let out = run("ykman", &["--reader", reader, "info"], None, Step::Ykman)?;
+        if !out.status.success() {
+            continue;
+        }
+        if let Ok(device) = parse_info(&String::from_utf8(out.stdout)?) {
+            if device.serial == serial {
+                return Ok(reader.to_string());
+            }
+        }
+    }
+    Err(WorkerError::SerialNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apps(names: &[&str]) -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_serials() {
+        assert_eq!(
+            parse_serials(include_str!("../tests/fixtures/ykman/list_serials.txt")),
+            apps(&["12345678", "23456789"])
+        );
+        assert!(parse_serials(include_str!(
+            "../tests/fixtures/ykman/list_serials_empty.txt"
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn test_parse_info_ykman3() {
+        let device =
+            parse_info(include_str!("../tests/fixtures/ykman/info_ykman3_5nfc.txt")).unwrap();
+        assert_eq!(
+            device,
+            YubiKeyDevice {
+                serial: "12345678".into(),
+                device_type: "YubiKey 5 NFC".into(),
+                firmware: Some(FirmwareVersion::new(5, 2, 7)),
+                form_factor: "Keychain (USB-A)".into(),
+                usb_applications: apps(&["OTP", "FIDO U2F", "OpenPGP", "PIV", "OATH", "FIDO2"]),
+                nfc_applications: apps(&["OTP", "FIDO U2F", "OpenPGP", "OATH", "FIDO2"]),
+                fips: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_info_ykman4_without_nfc() {
+        let device =
+            parse_info(include_str!("../tests/fixtures/ykman/info_ykman4_5c.txt")).unwrap();
+        assert_eq!(device.serial, "23456789");
+        assert_eq!(device.firmware, Some(FirmwareVersion::new(5, 1, 2)));
+        assert_eq!(device.form_factor, "Keychain (USB-C)");
+        assert_eq!(
+            device.usb_applications,
+            apps(&["Yubico OTP", "FIDO U2F", "FIDO2", "OATH", "PIV"])
+        );
+        assert!(device.nfc_applications.is_empty());
+        assert!(!device.usb_applications.contains(&"OpenPGP".to_string()));
+        assert!(!device.fips);
+    }
+
+    #[test]
+    fn test_parse_info_ykman5_fips() {
+        let device = parse_info(include_str!(
+            "../tests/fixtures/ykman/info_ykman5_5nfc_fips.txt"
+        ))
+        .unwrap();
+        assert_eq!(device.serial, "34567890");
+        assert_eq!(device.device_type, "YubiKey 5 NFC FIPS");
+        assert_eq!(device.firmware, Some(FirmwareVersion::new(5, 7, 2)));
+        assert!(device.usb_applications.contains(&"OpenPGP".to_string()));
+        assert_eq!(device.nfc_applications.len(), 7);
+        assert!(device.fips);
+    }
+
+    #[test]
+    fn test_parse_info_fips_series_4() {
+        let device = parse_info(include_str!(
+            "../tests/fixtures/ykman/info_ykman4_fips4.txt"
+        ))
+        .unwrap();
+        assert_eq!(device.serial, "45678901");
+        assert_eq!(device.firmware, Some(FirmwareVersion::new(4, 4, 5)));
+        assert_eq!(
+            device.usb_applications,
+            apps(&["OTP", "FIDO U2F", "OATH", "PIV", "OpenPGP"])
+        );
+        assert!(device.fips);
+    }
+
+    #[test]
+    fn test_parse_info_without_serial() {
+        assert!(parse_info("Device type: YubiKey 5 NFC\n").is_err());
+    }
+
+    #[test]
+    fn test_firmware_version() {
+        assert_eq!(
+            "5.4.3".parse::<FirmwareVersion>().unwrap(),
+            FirmwareVersion::new(5, 4, 3)
+        );
+        assert!("Uncertain, re-run with only one YubiKey connected"
+            .parse::<FirmwareVersion>()
+            .is_err());
+        assert!(FirmwareVersion::new(5, 2, 3) > FirmwareVersion::new(4, 4, 5));
+    }
+}